@@ -0,0 +1,129 @@
+//! Compares parsing and flattening records through `serde_json::Value` (copying the key path
+//! for every key, and every key and string value) with the borrowing `JsonValue` walk.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use modality_api::{AttrKey, AttrVal};
+use modality_json::config::PluginConfig;
+use modality_json::json::JsonValue;
+use modality_json::mapper::{json_leaf_to_attr_val, walk_obj, JsonEventMapper, KeyPathFormatter};
+
+const NUM_RECORDS: usize = 1000;
+
+fn records() -> String {
+    (0..NUM_RECORDS)
+        .map(|i| {
+            serde_json::json!({
+                "component": format!("component-{}", i % 8),
+                "msg": "sensor reading",
+                "timestamp": 1_700_000_000_000u64 + i as u64,
+                "seqnum": i,
+                "reading": {
+                    "sensor": {"id": "temp-1", "location": {"building": "b2", "room": "lab"}},
+                    "value": 55.2,
+                    "units": "celsius",
+                    "samples": [55.1, 55.2, 55.4, 55.0],
+                },
+                "tags": ["calibrated", "nominal"],
+                "host": {"name": "node-17", "os": {"name": "linux", "version": "6.1"}},
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The previous flattening: the path is copied for every key, and joined into a new string
+fn flatten_value(val: &serde_json::Value) -> Vec<(AttrKey, AttrVal)> {
+    fn walk(path: &[String], val: &serde_json::Value, kvs: &mut Vec<(AttrKey, AttrVal)>) {
+        match val {
+            serde_json::Value::Object(obj) => {
+                for (k, v) in obj.iter() {
+                    let mut path = path.to_vec();
+                    path.push(k.clone());
+                    walk(&path, v, kvs);
+                }
+            }
+            serde_json::Value::Array(vals) => {
+                for (i, v) in vals.iter().enumerate() {
+                    let mut path = path.to_vec();
+                    path.push(i.to_string());
+                    walk(&path, v, kvs);
+                }
+            }
+            serde_json::Value::String(s) => kvs.push((
+                AttrKey::new(path.join(".")),
+                AttrVal::String(s.clone().into()),
+            )),
+            serde_json::Value::Null => (),
+            other => {
+                if let Some(v) = json_leaf_to_attr_val(&JsonValue::from(other)) {
+                    kvs.push((AttrKey::new(path.join(".")), v));
+                }
+            }
+        }
+    }
+
+    let mut kvs = vec![];
+    walk(&[], val, &mut kvs);
+    kvs
+}
+
+fn flatten_json_value(
+    val: &JsonValue,
+    formatter: &mut KeyPathFormatter,
+) -> Vec<(AttrKey, AttrVal)> {
+    let mut kvs = vec![];
+    walk_obj(val.as_object().unwrap(), |key_path, val| {
+        if let Some(val) = json_leaf_to_attr_val(val) {
+            kvs.push((formatter.format(key_path), val));
+        }
+    });
+    kvs
+}
+
+fn flatten(c: &mut Criterion) {
+    let input = records();
+    let cfg = PluginConfig {
+        timeline_names: vec!["component".to_string()],
+        event_names: vec!["msg".to_string()],
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("flatten");
+    group.throughput(Throughput::Elements(NUM_RECORDS as u64));
+
+    group.bench_function("serde_json_value", |b| {
+        b.iter(|| {
+            for line in input.lines() {
+                let val: serde_json::Value = serde_json::from_str(line).unwrap();
+                black_box(flatten_value(&val));
+            }
+        })
+    });
+
+    group.bench_function("borrowed_json_value", |b| {
+        let mut formatter = KeyPathFormatter::new(&cfg);
+        b.iter(|| {
+            for line in input.lines() {
+                let val: JsonValue = serde_json::from_str(line).unwrap();
+                black_box(flatten_json_value(&val, &mut formatter));
+            }
+        })
+    });
+
+    // The whole mapping, including timeline and event names
+    group.bench_function("map_json_value", |b| {
+        let mut mapper = JsonEventMapper::new(&cfg).unwrap();
+        b.iter(|| {
+            for line in input.lines() {
+                let val: JsonValue = serde_json::from_str(line).unwrap();
+                black_box(mapper.map_json_value(&val, &[]).unwrap());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, flatten);
+criterion_main!(benches);
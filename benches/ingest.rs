@@ -0,0 +1,112 @@
+//! Compares unbatched (batch size 1) and batched ingest, for events on interleaved timelines,
+//! over a connection which simulates a fixed cost per ingest message.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use modality_api::{AttrKey, AttrVal, TimelineId};
+use modality_ingest_protocol::InternedAttrKey;
+use modality_json::client::Client;
+use modality_json::connection::IngestConnection;
+use modality_json::error::Error;
+use modality_json::sink::EventSink;
+use std::time::{Duration, Instant};
+
+const NUM_TIMELINES: usize = 8;
+const NUM_EVENTS: usize = 2000;
+const MESSAGE_COST: Duration = Duration::from_micros(20);
+
+struct SimulatedLatencyConnection {
+    next_key: u32,
+}
+
+impl SimulatedLatencyConnection {
+    fn message(&self) {
+        // Spin rather than sleep, since sleeps this short aren't precise
+        let start = Instant::now();
+        while start.elapsed() < MESSAGE_COST {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[async_trait]
+impl IngestConnection for SimulatedLatencyConnection {
+    async fn declare_attr_key(&mut self, _key: String) -> Result<InternedAttrKey, Error> {
+        self.message();
+        self.next_key += 1;
+        Ok(InternedAttrKey::from(self.next_key))
+    }
+
+    async fn open_timeline(&mut self, _id: TimelineId) -> Result<(), Error> {
+        self.message();
+        Ok(())
+    }
+
+    async fn timeline_metadata(
+        &mut self,
+        _attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        self.message();
+        Ok(())
+    }
+
+    async fn event(
+        &mut self,
+        _ordering: u128,
+        _attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        self.message();
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.message();
+        Ok(())
+    }
+}
+
+async fn import(timelines: &[TimelineId], batch_size: usize) {
+    let conn = SimulatedLatencyConnection { next_key: 0 };
+    let mut client = Client::with_connection(Box::new(conn), batch_size);
+
+    for i in 0..NUM_EVENTS {
+        let tl = i % NUM_TIMELINES;
+        let timeline_kvs = vec![
+            (AttrKey::new("name".into()), format!("tl{tl}").into()),
+            (AttrKey::new("run_id".into()), "bench".into()),
+        ];
+        let event_kvs = vec![
+            (AttrKey::new("name".into()), "ev".into()),
+            (AttrKey::new("seq".into()), AttrVal::Integer(i as i64)),
+            (
+                AttrKey::new(format!("field_{}", i % 16)),
+                AttrVal::Bool(true),
+            ),
+        ];
+        client
+            .send_event_on_timeline(timelines[tl], timeline_kvs, i as u128, event_kvs)
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+}
+
+fn ingest(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let timelines: Vec<TimelineId> = (0..NUM_TIMELINES).map(|_| TimelineId::allocate()).collect();
+
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Elements(NUM_EVENTS as u64));
+    group.sample_size(10);
+    for batch_size in [1, 100, 1000] {
+        group.bench_with_input(
+            BenchmarkId::new("interleaved_timelines", batch_size),
+            &batch_size,
+            |b, &batch_size| b.to_async(&rt).iter(|| import(&timelines, batch_size)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ingest);
+criterion_main!(benches);
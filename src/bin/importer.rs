@@ -1,17 +1,33 @@
+mod progress;
+
+use crate::progress::{Progress, ProgressMode};
 use clap::Parser;
-use fxhash::FxHashMap;
-use itertools::Itertools;
-use modality_api::types::TimelineId;
-use modality_api::{AttrKey, AttrVal, BigInt};
-use modality_ingest_client::IngestClient;
-use modality_json::config::{AttrKeyRename, TimestampUnit};
+use memmap2::Mmap;
+use modality_api::{AttrKey, AttrVal};
+use modality_json::checkpoint::{ImportState, InputFingerprint, InputState, TimelineState};
+use modality_json::config::{
+    AttrCoercion, AttrKeyRename, CoercionErrorAction, KeySegmentPolicy, LineDisposition,
+    NullPolicy, RenameKind, TimestampUnit, UnmatchedLineAction,
+};
+use modality_json::connection::{IngestConnector, RetryPolicy, TcpIngestConnector};
+use modality_json::json::JsonValue;
+use modality_json::mapper::{
+    JsonEventMapper, JsonPrefix, MappedEvent, NonJsonLine, NonJsonLineParser, TimelineRegistry,
+};
+use modality_json::record::RecordingConnection;
+use modality_json::report::ImportReport;
+use modality_json::sink::{EventSink, FileSink, RenamingSink, StdoutSink};
 use modality_json::{prelude::*, tracing::try_init_tracing_subscriber};
-use regex::Regex;
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::{path::PathBuf, time::Duration};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tracing::{error, warn};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
 /// Import JSON data from files
@@ -60,7 +76,8 @@ pub struct Opts {
     #[clap(long, name = "trace-name", help_heading = "IMPORT CONFIGURATION")]
     pub dry_run: bool,
 
-    /// Rename a timeline attribute key as it is being imported. Specify as 'original_key,new_key'
+    /// Rename a timeline attribute key as it is being imported. Specify as 'original_key,new_key'.
+    /// Prefix the original key with 'regex:' or 'glob:' to match it as a pattern.
     #[clap(
         long = "rename-timeline-attr",
         name = "original.tl.attr,new.tl.attr",
@@ -69,7 +86,8 @@ pub struct Opts {
     )]
     pub rename_timeline_attrs: Vec<AttrKeyRename>,
 
-    /// Rename an event attribute key as it is being imported. Specify as 'original_key,new_key'
+    /// Rename an event attribute key as it is being imported. Specify as 'original_key,new_key'.
+    /// Prefix the original key with 'regex:' or 'glob:' to match it as a pattern.
     #[clap(
         long = "rename-event-attr",
         name = "original.event.attr,new.event.attr",
@@ -78,6 +96,39 @@ pub struct Opts {
     )]
     pub rename_event_attrs: Vec<AttrKeyRename>,
 
+    /// The string used to join the segments of a flattened json key path
+    /// into an attr key. Defaults to '.'.
+    #[clap(long, name = "separator", help_heading = "IMPORT CONFIGURATION")]
+    pub key_path_separator: Option<String>,
+
+    /// What to do with characters in a json key segment that aren't valid in
+    /// an attr key. One of keep, replace, strip.
+    #[clap(long, name = "policy", help_heading = "IMPORT CONFIGURATION")]
+    pub key_segment_policy: Option<KeySegmentPolicy>,
+
+    /// The replacement string used by the 'replace' key segment policy. Defaults to '_'.
+    #[clap(long, name = "replacement", help_heading = "IMPORT CONFIGURATION")]
+    pub key_segment_replacement: Option<String>,
+
+    /// What to do with json null values. One of drop, sentinel, is-null, fail.
+    #[clap(long, name = "null-policy", help_heading = "IMPORT CONFIGURATION")]
+    pub null_policy: Option<NullPolicy>,
+
+    /// The string value used for nulls by the 'sentinel' null policy. Defaults to 'null'.
+    #[clap(long, name = "sentinel", help_heading = "IMPORT CONFIGURATION")]
+    pub null_sentinel: Option<String>,
+
+    /// Coerce the value of an attr to a specific type. Specify as 'key,type' or
+    /// 'key,type,on-error'. The type is one of string, int, float, bool, hex-int,
+    /// uuid, timestamp, timeline-id. on-error is one of fail (the default) or fallback.
+    #[clap(
+        long = "coerce-attr",
+        name = "key,type",
+        help_heading = "IMPORT CONFIGURATION",
+        value_parser = parse_attr_coercion
+    )]
+    pub coerce_attrs: Vec<AttrCoercion>,
+
     /// The json path where the event's timestamp can be found
     #[clap(long = "timestamp-attr", help_heading = "IMPORT CONFIGURATION")]
     pub timestamp_attr: Option<String>,
@@ -100,7 +151,8 @@ pub struct Opts {
 
     /// The name for an attr to use for data extracted from subgroupbs
     /// in --non-json-regex. These are treated positionally, with
-    /// respect to the subgroup's position in the regex.
+    /// respect to the subgroup's position in the regex. If not given,
+    /// the regex's named capture groups ('(?P<name>...)') are used as attr keys.
     #[clap(
         long = "non-json-attr",
         name = "attr-key",
@@ -108,6 +160,118 @@ pub struct Opts {
     )]
     pub non_json_attrs: Vec<String>,
 
+    /// If a line starts with text matching this regex, followed by a json
+    /// object, parse the json object and merge the attrs extracted from the
+    /// prefix into it.
+    #[clap(long, name = "prefix-regex", help_heading = "IMPORT CONFIGURATION")]
+    pub json_prefix_regex: Option<String>,
+
+    /// The name for an attr to use for data extracted from subgroups in
+    /// --json-prefix-regex, treated positionally. If not given, the regex's named
+    /// capture groups are used.
+    #[clap(
+        long = "json-prefix-attr",
+        name = "prefix-attr-key",
+        help_heading = "IMPORT CONFIGURATION"
+    )]
+    pub json_prefix_attrs: Vec<String>,
+
+    /// What to do with a non-json line that matches no rule. One of skip, warn, fail.
+    #[clap(long, name = "action", help_heading = "IMPORT CONFIGURATION")]
+    pub unmatched_line_action: Option<UnmatchedLineAction>,
+
+    /// Group non-json lines into multi-line records, each starting with a line
+    /// matching this regex.
+    #[clap(long, name = "start-regex", help_heading = "IMPORT CONFIGURATION")]
+    pub multiline_start_regex: Option<String>,
+
+    /// Group non-json lines into multi-line records, where lines starting with
+    /// whitespace continue the previous line.
+    #[clap(long = "multiline-indent", help_heading = "IMPORT CONFIGURATION")]
+    pub multiline_indent_continuation: bool,
+
+    /// The attr key used for the continuation lines of a multi-line record
+    #[clap(long, name = "body-attr-key", help_heading = "IMPORT CONFIGURATION")]
+    pub multiline_body_attr: Option<String>,
+
+    /// Skip records which can't be parsed or mapped, instead of failing the import
+    #[clap(long, help_heading = "IMPORT CONFIGURATION")]
+    pub lenient: bool,
+
+    /// In lenient mode, write skipped records (and their location) to this file, as json lines
+    #[clap(long, name = "dead-letter-path", help_heading = "IMPORT CONFIGURATION")]
+    pub dead_letter_file: Option<PathBuf>,
+
+    /// Allow a dead letter file when redaction rules are configured. Dead letters hold the
+    /// raw record text, which isn't redacted.
+    #[clap(long, help_heading = "IMPORT CONFIGURATION")]
+    pub dead_letter_unredacted: bool,
+
+    /// In lenient mode, fail the import once more than this many records have been skipped
+    #[clap(long, name = "count", help_heading = "IMPORT CONFIGURATION")]
+    pub max_errors: Option<u64>,
+
+    /// How to report progress on stderr while importing. One of auto, bar, log, none.
+    /// 'auto' shows a progress bar when stderr is a terminal, and log lines otherwise.
+    #[clap(
+        long,
+        name = "mode",
+        default_value = "auto",
+        help_heading = "IMPORT CONFIGURATION"
+    )]
+    pub progress: ProgressMode,
+
+    /// Write the import summary report to this file, as json
+    #[clap(long, name = "report-path", help_heading = "IMPORT CONFIGURATION")]
+    pub report_json: Option<PathBuf>,
+
+    /// Record the progress of the import in this file, so that it can be resumed with --resume
+    #[clap(long, name = "state-path", help_heading = "IMPORT CONFIGURATION")]
+    pub state_file: Option<PathBuf>,
+
+    /// Resume an interrupted import from the state file, instead of starting over
+    #[clap(long, help_heading = "IMPORT CONFIGURATION")]
+    pub resume: bool,
+
+    /// Save the import state after this many records. Defaults to 10000.
+    #[clap(long, name = "records", help_heading = "IMPORT CONFIGURATION")]
+    pub checkpoint_interval: Option<u64>,
+
+    /// The number of events to buffer before sending them. Defaults to 1000.
+    #[clap(long, name = "events", help_heading = "IMPORT CONFIGURATION")]
+    pub ingest_batch_size: Option<usize>,
+
+    /// How many times to try reconnecting when the ingest connection fails. Defaults to 10.
+    #[clap(long, name = "attempts", help_heading = "IMPORT CONFIGURATION")]
+    pub reconnect_attempts: Option<u32>,
+
+    /// Write the ingest stream to this file instead of sending it to modality.
+    /// Use modality-json-replay to send it later.
+    #[clap(
+        long = "record",
+        name = "recording-path",
+        help_heading = "IMPORT CONFIGURATION"
+    )]
+    pub record_file: Option<PathBuf>,
+
+    /// Write the mapped events to this file, as json lines, instead of sending them to modality
+    #[clap(long, name = "output-path", help_heading = "IMPORT CONFIGURATION")]
+    pub output_file: Option<PathBuf>,
+
+    /// How many input files to parse at the same time. Defaults to the number of CPUs.
+    /// Each job holds up to 16k mapped records which are waiting to be imported.
+    #[clap(long, name = "jobs", help_heading = "IMPORT CONFIGURATION")]
+    pub parse_jobs: Option<usize>,
+
+    /// Treat the inputs as newline-delimited json, with at most one record per line,
+    /// so that each input can be split into chunks which are parsed in parallel
+    #[clap(long, help_heading = "IMPORT CONFIGURATION")]
+    pub ndjson: bool,
+
+    /// The approximate size of the chunks ndjson inputs are split into. Defaults to 16 MiB.
+    #[clap(long, name = "bytes", help_heading = "IMPORT CONFIGURATION")]
+    pub ndjson_chunk_size: Option<u64>,
+
     /// Path to trace directories
     #[clap(name = "input", help_heading = "IMPORT CONFIGURATION")]
     pub inputs: Vec<PathBuf>,
@@ -119,24 +283,161 @@ fn parse_attr_key_rename(
     let pos = s
         .find(',')
         .ok_or_else(|| format!("invalid original,new: no `,` found in `{}`", s))?;
-    let original = s[..pos].parse()?;
+    let (kind, original) = if let Some(re) = s[..pos].strip_prefix("regex:") {
+        (RenameKind::Regex, re)
+    } else if let Some(glob) = s[..pos].strip_prefix("glob:") {
+        (RenameKind::Glob, glob)
+    } else {
+        (RenameKind::Exact, &s[..pos])
+    };
     let new = s[pos + 1..].parse()?;
-    Ok(AttrKeyRename { original, new })
+    Ok(AttrKeyRename {
+        original: original.to_string(),
+        new,
+        kind,
+    })
+}
+
+fn parse_attr_coercion(
+    s: &str,
+) -> Result<AttrCoercion, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut parts = s.split(',');
+    let (Some(path), Some(to)) = (parts.next(), parts.next()) else {
+        return Err(format!("invalid key,type: no `,` found in `{}`", s).into());
+    };
+    let on_error = parts
+        .next()
+        .map(|a| a.parse::<CoercionErrorAction>())
+        .transpose()?
+        .unwrap_or_default();
+    Ok(AttrCoercion {
+        path: path.to_string(),
+        to: to.parse()?,
+        on_error,
+        units: None,
+    })
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("At least input JSON file is required.")]
     MissingInputs,
+
+    #[error("Failed to initialize tracing. {0}")]
+    Tracing(#[from] tracing_subscriber::util::TryInitError),
+
+    #[error("Failed to load the configuration. {0}")]
+    Config(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Failed to read input '{path}'. {source}")]
+    ReadInput {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse JSON. {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{location}: {error}\n    {snippet}")]
+    Located {
+        location: Location,
+        snippet: String,
+        error: Box<Error>,
+    },
+
+    #[error("Exceeded the maximum number of errors ({max_errors}). The last one was at {last}")]
+    MaxErrorsExceeded {
+        max_errors: u64,
+        /// The error which exceeded the maximum, with its location
+        #[source]
+        last: Box<Error>,
+    },
+
+    #[error("Resuming an import requires a state file. Use --state-file or the 'state-file' config key.")]
+    ResumeWithoutStateFile,
+
+    #[error("Failed to load the import state from '{path}'. {source}")]
+    LoadState {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Can't resume '{path}' at offset {offset}; the input has changed since it was checkpointed.")]
+    InvalidResumeOffset { path: PathBuf, offset: usize },
+
+    #[error("Can't resume '{0}'; it has changed since it was checkpointed.")]
+    InputChanged(PathBuf),
+
+    #[error("Parsing '{0}' stopped before the end of the input.")]
+    WorkerStopped(PathBuf),
+
+    #[error("Multi-line records can't be used with ndjson inputs, which have at most one record per line.")]
+    NdjsonMultiline,
+
+    #[error("Attrs from non-json lines can't be attached to the next json object in ndjson inputs, which are parsed in independent chunks. Use the 'event' or 'discard' disposition instead.")]
+    NdjsonAttach,
+
+    #[error("A dead letter file is only written in lenient mode. Use --lenient or the 'lenient' config key.")]
+    DeadLetterFileWithoutLenient,
+
+    #[error("Dead letters hold the raw record text, which isn't redacted. Use --dead-letter-unredacted or the 'dead-letter-unredacted' config key to write them anyway.")]
+    DeadLetterFileWithRedaction,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+
+    #[error(transparent)]
+    Auth(#[from] modality_json::auth::AuthTokenError),
+
+    #[error("Encountered an ingest client initialization error. {0}")]
+    IngestClientInitialization(#[from] modality_ingest_client::IngestClientInitializationError),
+
+    #[error("Encountered an ingest client error. {0}")]
+    Ingest(#[from] modality_ingest_client::IngestError),
+
+    #[error(transparent)]
+    Plugin(#[from] modality_json::error::Error),
+}
+
+/// Where in the input a record (or error) was found
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, in bytes
+    pub column: usize,
+    /// Byte offset from the start of the input
+    pub offset: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{} (byte offset {})",
+            self.path.display(),
+            self.line,
+            self.column,
+            self.offset
+        )
+    }
 }
 
 #[tokio::main]
 async fn main() {
     match do_main().await {
-        Ok(()) => (),
+        Ok(None) => (),
+        Ok(Some(signal)) => std::process::exit(signal.exit_code()),
         Err(e) => {
             eprintln!("{}", e);
-            let mut cause = e.source();
+            let mut cause = std::error::Error::source(&e);
             while let Some(err) = cause {
                 eprintln!("Caused by: {err}");
                 cause = err.source();
@@ -146,34 +447,134 @@ async fn main() {
     }
 }
 
-type TimelineNameSig = (AttrKey, AttrVal);
+/// The signal which asked the importer to shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShutdownSignal {
+    Interrupt,
+    Terminate,
+}
 
-async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts = Opts::parse();
+impl ShutdownSignal {
+    /// 128 + the signal number, the way a shell reports a process killed by the signal
+    fn exit_code(self) -> i32 {
+        match self {
+            ShutdownSignal::Interrupt => 130,
+            ShutdownSignal::Terminate => 143,
+        }
+    }
+}
 
-    try_init_tracing_subscriber()?;
+impl std::fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownSignal::Interrupt => f.write_str("SIGINT"),
+            ShutdownSignal::Terminate => f.write_str("SIGTERM"),
+        }
+    }
+}
 
-    let intr = Interruptor::new();
-    let interruptor = intr.clone();
-    ctrlc::set_handler(move || {
-        if intr.is_set() {
-            // 128 (fatal error signal "n") + 2 (control-c is fatal error signal 2)
-            std::process::exit(130);
-        } else {
+/// Set the interruptor when SIGINT or SIGTERM is received, so the import can stop after the
+/// current record and shut down in an orderly way. A second signal exits immediately.
+fn spawn_signal_handler(intr: Interruptor) -> Result<Arc<OnceLock<ShutdownSignal>>, Error> {
+    let received = Arc::new(OnceLock::new());
+    let mut signals = Signals::new()?;
+
+    let received_by_handler = received.clone();
+    tokio::spawn(async move {
+        while let Some(signal) = signals.recv().await {
+            if received_by_handler.set(signal).is_err() {
+                std::process::exit(signal.exit_code());
+            }
+            warn!("Received {signal}, shutting down. Send it again to exit immediately.");
             intr.set();
         }
-    })?;
+    });
+
+    Ok(received)
+}
+
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
 
-    let mut cfg = JsonConfig::load_merge_with_opts(opts.rf_opts)?;
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Result<Self, Error> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Option<ShutdownSignal> {
+        tokio::select! {
+            s = self.interrupt.recv() => s.map(|_| ShutdownSignal::Interrupt),
+            s = self.terminate.recv() => s.map(|_| ShutdownSignal::Terminate),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Result<Self, Error> {
+        Ok(Signals)
+    }
+
+    async fn recv(&mut self) -> Option<ShutdownSignal> {
+        tokio::signal::ctrl_c()
+            .await
+            .ok()
+            .map(|_| ShutdownSignal::Interrupt)
+    }
+}
+
+/// Returns the signal which interrupted the import, if any
+async fn do_main() -> Result<Option<ShutdownSignal>, Error> {
+    let start = Instant::now();
+    let opts = Opts::parse();
+
+    try_init_tracing_subscriber()?;
+
+    let interruptor = Interruptor::new();
+    let shutdown_signal = spawn_signal_handler(interruptor.clone())?;
+
+    let mut cfg = JsonConfig::load_merge_with_opts(opts.rf_opts).map_err(Error::Config)?;
     cfg.plugin.import.inputs.extend(opts.inputs);
     cfg.plugin.event_names.extend(opts.event_names);
     cfg.plugin.timeline_names.extend(opts.timeline_names);
     cfg.plugin.timeline_attrs.extend(opts.timeline_attrs);
+    cfg.plugin.coerce_attrs.extend(opts.coerce_attrs);
 
     if opts.timeline_name_prefix.is_some() {
         cfg.plugin.timeline_name_prefix = opts.timeline_name_prefix;
     }
 
+    if opts.key_path_separator.is_some() {
+        cfg.plugin.key_path_separator = opts.key_path_separator;
+    }
+
+    if opts.key_segment_policy.is_some() {
+        cfg.plugin.key_segment_policy = opts.key_segment_policy;
+    }
+
+    if opts.key_segment_replacement.is_some() {
+        cfg.plugin.key_segment_replacement = opts.key_segment_replacement;
+    }
+
+    if opts.null_policy.is_some() {
+        cfg.plugin.null_policy = opts.null_policy;
+    }
+
+    if opts.null_sentinel.is_some() {
+        cfg.plugin.null_sentinel = opts.null_sentinel;
+    }
+
     if opts.timestamp_attr.is_some() {
         cfg.plugin.timestamp_attr = opts.timestamp_attr;
     }
@@ -190,7 +591,135 @@ async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         cfg.plugin.non_json_attrs = opts.non_json_attrs;
     }
 
-    let run_id = AttrVal::from(cfg.plugin.run_id.unwrap_or_else(Uuid::new_v4).to_string());
+    if opts.json_prefix_regex.is_some() {
+        cfg.plugin.json_prefix_regex = opts.json_prefix_regex;
+    }
+
+    if !opts.json_prefix_attrs.is_empty() {
+        cfg.plugin.json_prefix_attrs = opts.json_prefix_attrs;
+    }
+
+    if opts.unmatched_line_action.is_some() {
+        cfg.plugin.unmatched_line_action = opts.unmatched_line_action;
+    }
+
+    if opts.multiline_start_regex.is_some() {
+        cfg.plugin.multiline_start_regex = opts.multiline_start_regex;
+    }
+
+    if opts.multiline_indent_continuation {
+        cfg.plugin.multiline_indent_continuation = true;
+    }
+
+    if opts.multiline_body_attr.is_some() {
+        cfg.plugin.multiline_body_attr = opts.multiline_body_attr;
+    }
+
+    if opts.lenient {
+        cfg.plugin.lenient = true;
+    }
+
+    if opts.dead_letter_file.is_some() {
+        cfg.plugin.dead_letter_file = opts.dead_letter_file;
+    }
+
+    if opts.dead_letter_unredacted {
+        cfg.plugin.dead_letter_unredacted = true;
+    }
+
+    if opts.max_errors.is_some() {
+        cfg.plugin.max_errors = opts.max_errors;
+    }
+
+    if opts.state_file.is_some() {
+        cfg.plugin.state_file = opts.state_file;
+    }
+
+    if opts.checkpoint_interval.is_some() {
+        cfg.plugin.checkpoint_interval = opts.checkpoint_interval;
+    }
+
+    if opts.ingest_batch_size.is_some() {
+        cfg.plugin.ingest_batch_size = opts.ingest_batch_size;
+    }
+
+    if opts.reconnect_attempts.is_some() {
+        cfg.plugin.reconnect_attempts = opts.reconnect_attempts;
+    }
+
+    if opts.record_file.is_some() {
+        cfg.plugin.record_file = opts.record_file;
+    }
+
+    if opts.output_file.is_some() {
+        cfg.plugin.output_file = opts.output_file;
+    }
+
+    if opts.parse_jobs.is_some() {
+        cfg.plugin.parse_jobs = opts.parse_jobs;
+    }
+
+    if opts.ndjson {
+        cfg.plugin.ndjson = true;
+    }
+
+    if opts.ndjson_chunk_size.is_some() {
+        cfg.plugin.ndjson_chunk_size = opts.ndjson_chunk_size;
+    }
+
+    if cfg.plugin.ndjson
+        && (cfg.plugin.multiline_start_regex.is_some() || cfg.plugin.multiline_indent_continuation)
+    {
+        return Err(Error::NdjsonMultiline);
+    }
+
+    if cfg.plugin.ndjson
+        && (cfg.plugin.non_json_regex.is_some()
+            || cfg
+                .plugin
+                .non_json_rules
+                .iter()
+                .any(|r| r.disposition == LineDisposition::Attach))
+    {
+        return Err(Error::NdjsonAttach);
+    }
+
+    if cfg.plugin.dead_letter_file.is_some() && !cfg.plugin.lenient {
+        return Err(Error::DeadLetterFileWithoutLenient);
+    }
+
+    if cfg.plugin.dead_letter_file.is_some()
+        && !cfg.plugin.redact.is_empty()
+        && !cfg.plugin.dead_letter_unredacted
+    {
+        return Err(Error::DeadLetterFileWithRedaction);
+    }
+
+    let mut state = match (&cfg.plugin.state_file, opts.resume) {
+        (Some(path), true) => ImportState::load(path).map_err(|source| Error::LoadState {
+            path: path.clone(),
+            source,
+        })?,
+        (None, true) => return Err(Error::ResumeWithoutStateFile),
+        (_, false) => ImportState::default(),
+    };
+    // Dry runs and output files don't import anything, so they mustn't mark inputs as imported
+    let checkpoint_file = match &cfg.plugin.state_file {
+        Some(_) if opts.dry_run || cfg.plugin.output_file.is_some() => {
+            warn!("Not updating the state file, since nothing is being imported");
+            None
+        }
+        state_file => state_file.clone(),
+    };
+    let checkpoint_interval = cfg.plugin.checkpoint_interval.unwrap_or(10_000).max(1);
+
+    // A resumed import keeps the run id of the original run
+    let run_id_uuid = state
+        .run_id
+        .or(cfg.plugin.run_id)
+        .unwrap_or_else(Uuid::new_v4);
+    state.run_id = Some(run_id_uuid);
+    let run_id = AttrVal::from(run_id_uuid.to_string());
 
     let mut rename_timeline_attrs = opts.rename_timeline_attrs.clone();
     rename_timeline_attrs.extend(cfg.plugin.rename_timeline_attrs.clone());
@@ -204,105 +733,376 @@ async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let non_json_re = cfg
-        .plugin
-        .non_json_regex
-        .as_deref()
-        .map(Regex::new)
-        .transpose()?;
-    let non_json_attrs = cfg
-        .plugin
-        .non_json_attrs
-        .iter()
-        .map(|k| AttrKey::new(k.clone()))
-        .collect::<Vec<_>>();
+    let parsers = Arc::new(InputParsers {
+        non_json: NonJsonLineParser::new(&cfg.plugin)?,
+        json_prefix: JsonPrefix::new(&cfg.plugin)?,
+    });
 
-    let mut known_timelines: FxHashMap<TimelineNameSig, TimelineId> = FxHashMap::default();
+    // Timeline identities are shared by all of the workers' mappers, so the same
+    // timeline gets the same id regardless of which input (or chunk) it came from
+    let mapper = JsonEventMapper::new(&cfg.plugin)?
+        .with_known_timelines(state.timelines.drain(..).map(TimelineState::into_parts));
+    let timelines = mapper.timeline_registry().clone();
 
     if cfg.plugin.import.inputs.is_empty() {
         error!("No input files provided.");
-        return Ok(());
+        return Ok(None);
     }
 
-    let c = IngestClient::connect_with_timeout(
-        &cfg.protocol_parent_url()?,
-        cfg.ingest.allow_insecure_tls,
-        Duration::from_secs(cfg.plugin.timeout_seconds.unwrap_or(1)),
-    )
-    .await?;
-    let c_authed = c.authenticate(cfg.resolve_auth()?.into()).await?;
-    let mut client = Client::new(c_authed, rename_timeline_attrs, rename_event_attrs);
-
-    'outer: for p in cfg.plugin.import.inputs.iter() {
-        // Use a single ordering counter for each input. This will
-        // likely fail if we get the same timeline from two different
-        // files... which is exactly what we want to happen.
-        let mut ordering = 0u128;
-
-        let buf = std::fs::read_to_string(p)?;
-        let mut s = buf.as_str();
+    let batch_size = cfg
+        .plugin
+        .ingest_batch_size
+        .unwrap_or(modality_json::client::DEFAULT_BATCH_SIZE);
+    // Renames are applied before any sink, so a dry run or output file
+    // shows the same attr keys that an import would send
+    let sink: Box<dyn EventSink> = if opts.dry_run {
+        Box::new(StdoutSink)
+    } else if let Some(output_file) = &cfg.plugin.output_file {
+        Box::new(FileSink::create(output_file)?)
+    } else if let Some(record_file) = &cfg.plugin.record_file {
+        Box::new(Client::with_connection(
+            Box::new(RecordingConnection::create(record_file, opts.resume)?),
+            batch_size,
+        ))
+    } else {
+        let connector = TcpIngestConnector {
+            url: cfg.protocol_parent_url()?,
+            allow_insecure_tls: cfg.ingest.allow_insecure_tls,
+            timeout: Duration::from_secs(cfg.plugin.timeout_seconds.unwrap_or(1)),
+            auth_token: cfg.resolve_auth()?.into(),
+        };
+        let retry_policy = RetryPolicy {
+            max_attempts: cfg
+                .plugin
+                .reconnect_attempts
+                .unwrap_or(RetryPolicy::default().max_attempts),
+            ..Default::default()
+        };
+        Box::new(
+            Client::with_connection(connector.connect().await?, batch_size)
+                .with_reconnect(Box::new(connector), retry_policy)
+                .with_interruptor(interruptor.clone()),
+        )
+    };
+    let mut sink = RenamingSink::new(sink, rename_timeline_attrs, rename_event_attrs)?;
+
+    let mut dead_letters = DeadLetters::new(&cfg.plugin, opts.resume)?;
+    let mut report = ImportReport::default();
+
+    // Inputs are parsed and mapped on a pool of workers, but their results are
+    // consumed in input order, so the import is the same as a sequential one.
+    let mut workers = vec![];
+    let mut inputs = vec![];
+    // The bytes left to import; a resumed import skips what was already imported
+    let mut total_bytes = Some(0);
+    // Ndjson inputs are also split into chunks, each with its own worker.
+    let chunk_size = cfg
+        .plugin
+        .ndjson_chunk_size
+        .unwrap_or(DEFAULT_NDJSON_CHUNK_SIZE)
+        .max(1) as usize;
+    for p in cfg.plugin.import.inputs.iter() {
+        let mut input_state = state.inputs.get(p).cloned().unwrap_or_default();
+        if input_state.complete {
+            debug!("Skipping '{}', it was already imported", p.display());
+            continue;
+        }
 
-        let mut extra_kvs = vec![];
-        let mut pending_json_vals = vec![];
+        // The checkpointed offset and orderings are only meaningful for the same input
+        let fingerprint = InputFingerprint::of(p).ok();
+        if input_state.fingerprint.is_some() && input_state.fingerprint != fingerprint {
+            return Err(Error::InputChanged(p.clone()));
+        }
+        total_bytes = total_bytes
+            .zip(fingerprint.as_ref())
+            .map(|(total, f)| total + f.len.saturating_sub(input_state.offset as u64));
+        input_state.fingerprint = fingerprint;
+
+        let chunks = if cfg.plugin.ndjson {
+            map_chunks(p, input_state.offset, chunk_size)?
+                .into_iter()
+                .map(Some)
+                .collect()
+        } else {
+            vec![None]
+        };
+        let mut receivers = vec![];
+        for chunk in chunks {
+            let (tx, rx) = mpsc::channel(PARSED_CHANNEL_CAPACITY);
+            workers.push(InputWorker {
+                path: p.clone(),
+                start_offset: chunk
+                    .as_ref()
+                    .map_or(input_state.offset, |c| c.start.max(input_state.offset)),
+                chunk,
+                mapper: mapper.clone(),
+                tx,
+            });
+            receivers.push(rx);
+        }
+        inputs.push((p, input_state, receivers));
+    }
+    let parse_jobs = cfg.plugin.parse_jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let scheduler = spawn_workers(workers, parsers, interruptor.clone(), parse_jobs);
+    let mut progress = Progress::new(opts.progress, total_bytes);
 
-        loop {
-            if interruptor.is_set() {
-                break 'outer;
-            }
+    // The error which stopped the import. The partial summary is still reported.
+    let mut import_error = None;
 
-            s = s.trim_start();
+    'outer: for (p, input_state, receivers) in inputs {
+        // Use an ordering counter for each timeline, per input. This will
+        // likely fail if we get the same timeline from two different
+        // files... which is exactly what we want to happen.
+        let mut orderings = input_state.orderings.clone();
+        // The orderings which have changed since the last safe point
+        let mut unsafe_orderings: Vec<(Uuid, u128)> = vec![];
+        let start_offset = input_state.offset;
+        let mut consumed = start_offset;
+
+        // The last point in the input where the import could be resumed
+        // without losing attrs from preceding non-json lines
+        let mut safe_point = input_state;
+        let mut last_checkpoint_records = report.records;
+
+        // The number of lines in the input's preceding chunks, which the
+        // workers don't know about when they report a location
+        let mut lines_before = 0;
+
+        let failed: Option<Error> = 'input: {
+            for mut rx in receivers {
+                let mut finished = None;
+
+                while let Some(batch) = rx.recv().await {
+                    if interruptor.is_set() {
+                        break;
+                    }
 
-            match s.chars().next() {
-                None => {
-                    break;
+                    for parsed in batch {
+                        match parsed {
+                            Parsed::Event(mut rts) => {
+                                // Only needed the first time the report sees the timeline
+                                let timeline_id = rts.timeline_id;
+                                let timeline_name =
+                                    (!report.has_timeline(&timeline_id)).then(|| {
+                                        rts.timeline_kvs
+                                            .iter()
+                                            .find(|(k, _)| k.as_ref() == "name")
+                                            .map(|(_, v)| v.to_string())
+                                            .unwrap_or_else(|| timeline_id.to_string())
+                                    });
+
+                                rts.timeline_kvs
+                                    .push((AttrKey::new("run_id".into()), run_id.clone()));
+                                let timeline = *rts.timeline_id.get_raw();
+                                let ordering = orderings.entry(timeline).or_default();
+                                if let Err(e) = sink
+                                    .send_event_on_timeline(
+                                        rts.timeline_id,
+                                        rts.timeline_kvs,
+                                        *ordering,
+                                        rts.event_kvs,
+                                    )
+                                    .await
+                                {
+                                    break 'input Some(e);
+                                }
+                                *ordering += 1;
+                                unsafe_orderings.push((timeline, *ordering));
+                                report
+                                    .count_event(timeline_id, || timeline_name.unwrap_or_default());
+                            }
+                            Parsed::Failed {
+                                error,
+                                mut location,
+                                record,
+                            } => {
+                                location.line += lines_before;
+                                if let Err(e) = dead_letters.record(error, location, &record) {
+                                    break 'input Some(e);
+                                }
+                            }
+                            Parsed::Filtered => report.records_filtered += 1,
+                            Parsed::Consumed {
+                                offset,
+                                records,
+                                resumable,
+                            } => {
+                                consumed = offset;
+                                report.records += records;
+                                progress.update(
+                                    report.bytes_processed + (consumed - start_offset) as u64,
+                                    report.records,
+                                );
+
+                                if !resumable {
+                                    continue;
+                                }
+                                safe_point.offset = offset;
+                                safe_point.orderings.extend(unsafe_orderings.drain(..));
+
+                                if let Some(state_file) = &checkpoint_file {
+                                    if report.records - last_checkpoint_records
+                                        >= checkpoint_interval
+                                    {
+                                        state.inputs.insert(p.clone(), safe_point.clone());
+                                        if let Err(e) = checkpoint(
+                                            sink.as_mut(),
+                                            &mut state,
+                                            &timelines,
+                                            state_file,
+                                        )
+                                        .await
+                                        {
+                                            break 'input Some(e);
+                                        }
+                                        last_checkpoint_records = report.records;
+                                    }
+                                }
+                            }
+                            Parsed::Finished {
+                                end,
+                                lines,
+                                dangling_attrs,
+                                ..
+                            } => {
+                                consumed = end;
+                                finished = Some((lines, dangling_attrs));
+                            }
+                            Parsed::Fatal(e) => break 'input Some(e),
+                        }
+                    }
                 }
-                Some(c) => {
-                    if c == '[' {
-                        let (s_prime, vals) = parse_array(s)?;
-                        pending_json_vals.extend(vals);
-                        s = s_prime;
-                    } else if c == '{' {
-                        let (s_prime, val) = parse_obj(s)?;
-                        pending_json_vals.push(val);
-                        s = s_prime;
-                    } else {
-                        let (s_prime, kvs) =
-                            parse_regex_line(s, non_json_re.as_ref(), &non_json_attrs)?;
-                        extra_kvs.extend(kvs);
-                        s = s_prime;
+
+                let Some((lines, dangling_attrs)) = finished else {
+                    // The worker only stops early when the import is interrupted
+                    if !interruptor.is_set() {
+                        break 'input Some(Error::WorkerStopped(p.clone()));
+                    }
+                    if let Some(state_file) = &checkpoint_file {
+                        state.inputs.insert(p.clone(), safe_point.clone());
+                        if let Err(e) =
+                            checkpoint(sink.as_mut(), &mut state, &timelines, state_file).await
+                        {
+                            break 'input Some(e);
+                        }
                     }
+                    report.bytes_processed += (consumed - start_offset) as u64;
+                    break 'outer;
+                };
+                lines_before += lines;
+
+                if dangling_attrs {
+                    warn!(
+                    "Attrs from non-json lines before byte offset {consumed} of '{}' were not attached to any json object",
+                    p.display()
+                );
                 }
             }
-
-            if !pending_json_vals.is_empty() {
-                for val in pending_json_vals.iter() {
-                    let mut rts =
-                        prepare_json_object(val, &extra_kvs, &cfg.plugin, &mut known_timelines)?;
-                    rts.timeline_kvs
-                        .push((AttrKey::new("run_id".into()), run_id.clone()));
-                    client
-                        .send_event_on_timeline(
-                            rts.timeline_id,
-                            rts.timeline_kvs,
-                            ordering,
-                            rts.event_kvs,
-                        )
-                        .await?;
-                    ordering += 1;
+            None
+        };
+
+        if let Some(e) = failed {
+            // Save how far the import got, so that it can be resumed. This is best effort:
+            // the import's error is the one reported, and nothing is saved if the events
+            // before the safe point can't be flushed.
+            if let Some(state_file) = &checkpoint_file {
+                state.inputs.insert(p.clone(), safe_point);
+                if let Err(save_err) =
+                    checkpoint(sink.as_mut(), &mut state, &timelines, state_file).await
+                {
+                    warn!("Failed to save the import state. {save_err}");
                 }
+            }
+            report.bytes_processed += (consumed - start_offset) as u64;
+            import_error = Some(e);
+            break;
+        }
 
-                pending_json_vals.clear();
-                extra_kvs.clear();
+        report.bytes_processed += (consumed - start_offset) as u64;
+        report.files_processed += 1;
+
+        if let Some(state_file) = &checkpoint_file {
+            state.inputs.insert(
+                p.clone(),
+                InputState {
+                    offset: consumed,
+                    orderings,
+                    fingerprint: safe_point.fingerprint,
+                    complete: true,
+                },
+            );
+            if let Err(e) = checkpoint(sink.as_mut(), &mut state, &timelines, state_file).await {
+                import_error = Some(e);
+                break;
             }
         }
     }
+    // Any workers still running stop once their results are dropped
+    scheduler.abort();
+
+    let flushed = match sink.flush().await {
+        Ok(()) => dead_letters.flush().map_err(Error::from),
+        Err(e) => Err(e),
+    };
+    let import_error = import_error.or(flushed.err());
+    progress.finish();
+
+    let signal = shutdown_signal.get().copied();
+    if let Some(signal) = signal {
+        eprintln!("Import interrupted by {signal}; the summary below is partial.");
+    } else if import_error.is_some() {
+        eprintln!("Import failed; the summary below is partial.");
+    }
+
+    report.records_skipped = dead_letters.num_errors;
+    report.finish(start.elapsed(), sink.declared_attr_key_count() as u64);
+    eprintln!("{report}");
+    let written = match &opts.report_json {
+        Some(path) => report.write_json(path),
+        None => Ok(()),
+    };
+
+    match (import_error, written) {
+        (Some(e), _) => Err(e),
+        (None, Err(e)) => Err(e.into()),
+        (None, Ok(())) => Ok(signal),
+    }
+}
 
+/// Flush the sink, so that everything before the checkpoint has been delivered, then save
+/// the import state
+async fn checkpoint(
+    sink: &mut dyn EventSink,
+    state: &mut ImportState,
+    timelines: &TimelineRegistry,
+    path: &Path,
+) -> Result<(), Error> {
+    sink.flush().await?;
+    save_state(state, timelines, path)
+}
+
+/// Record the known timelines in the import state, and write it to the state file
+fn save_state(
+    state: &mut ImportState,
+    timelines: &TimelineRegistry,
+    path: &Path,
+) -> Result<(), Error> {
+    state.timelines = timelines
+        .to_vec()
+        .iter()
+        .map(|((k, v), id)| TimelineState::new(k, v, id))
+        .collect();
+    state.save(path)?;
     Ok(())
 }
 
 fn json_from_str<'de, T: serde::Deserialize<'de>>(
     s: &'de str,
-) -> Result<(&str, T), Box<dyn std::error::Error>> {
+) -> Result<(&str, T), serde_json::Error> {
     let mut de = serde_json::Deserializer::from_str(s);
     let value: T = serde::de::Deserialize::deserialize(&mut de)?;
     let str_read = de.into_reader();
@@ -310,251 +1110,608 @@ fn json_from_str<'de, T: serde::Deserialize<'de>>(
     Ok((&s[str_read.index()..], value))
 }
 
-fn parse_array(s: &str) -> Result<(&str, Vec<serde_json::Value>), Box<dyn std::error::Error>> {
-    let (tail, json) = json_from_str::<serde_json::Value>(s)?;
-    let Some(vals) = json.as_array() else {
-        return Err("Expected JSON array".into());
-    };
-
-    Ok((tail, vals.clone()))
+/// Parse the json value at the front of 's'. A top-level array is flattened into its elements.
+fn parse_json_values(s: &str) -> Result<(&str, Vec<JsonValue<'_>>), serde_json::Error> {
+    let (tail, json) = json_from_str::<JsonValue>(s)?;
+    match json {
+        JsonValue::Array(vals) => Ok((tail, vals)),
+        val => Ok((tail, vec![val])),
+    }
 }
 
-fn parse_obj(s: &str) -> Result<(&str, serde_json::Value), Box<dyn std::error::Error>> {
-    let (tail, json) = json_from_str::<serde_json::Value>(s)?;
-    Ok((tail, json))
+/// The remainder of 's' after its first 'n' lines
+fn skip_lines(s: &str, n: usize) -> &str {
+    let mut rest = s;
+    for _ in 0..n {
+        match rest.find('\n') {
+            Some(i) => rest = &rest[i + 1..],
+            None => return &rest[rest.len()..],
+        }
+    }
+    rest
 }
 
-#[allow(clippy::type_complexity)]
-fn parse_regex_line<'a>(
-    s: &'a str,
-    re: Option<&Regex>,
-    attrs: &[AttrKey],
-) -> Result<(&'a str, Vec<(AttrKey, AttrVal)>), Box<dyn std::error::Error>> {
-    let re =
-        re.ok_or("Found non-json data. Please supply the '--non-json-regex' option to parse it.")?;
-
-    let line = s.lines().next().ok_or("Can't find line in inputs")?;
-    let tail = &s[line.len()..];
-
-    let mut out_attrs = vec![];
-
-    let caps = re
-        .captures_iter(line)
-        .next()
-        .ok_or_else(|| format!("Non-json line did not match the supplied regex.\n{s}"))?;
+/// The rest of 'buf' after the broken record which starts at 'offset': everything up to the
+/// next line which is indented no more than the record's first line, and doesn't close a json
+/// value. A broken multi-line (e.g. pretty-printed) json value is skipped as a whole.
+fn skip_broken_record(buf: &str, offset: usize) -> &str {
+    let line_start = buf[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let indent = offset - line_start;
+
+    let mut rest = skip_lines(&buf[offset..], 1);
+    while !rest.is_empty() {
+        let line = rest.split('\n').next().unwrap_or_default();
+        let trimmed = line.trim_start();
+        let line_indent = line.len() - trimmed.len();
+        if !trimmed.is_empty() && line_indent <= indent && !trimmed.starts_with(['}', ']']) {
+            break;
+        }
+        rest = skip_lines(rest, 1);
+    }
+    rest
+}
 
-    // the first capture is always the entire match
-    let caps = caps.iter().skip(1);
+/// The byte offset in 's' where the given json parse error occurred
+fn json_error_offset(s: &str, e: &serde_json::Error) -> usize {
+    let line_start = s.len() - skip_lines(s, e.line().saturating_sub(1)).len();
+    (line_start + e.column().saturating_sub(1)).min(s.len())
+}
 
-    for eob in attrs.iter().zip_longest(caps) {
-        match eob {
-            itertools::EitherOrBoth::Both(attr, capture) => {
-                let capture = capture.ok_or("Regex capture had no corresponding match")?;
-                out_attrs.push((attr.clone(), string_to_attr_val(capture.as_str())));
-            }
-            itertools::EitherOrBoth::Left(attr) => {
-                return Err(format!(
-                    "Requested non-json attr '{attr}' has no corresponding regex capature."
-                )
-                .into());
-            }
-            itertools::EitherOrBoth::Right(capture) => {
-                return Err(format!(
-                    "Regex capture {} has no corresponding attr; specify with --non-json-attr",
-                    capture.map(|c| c.as_str()).ok_or("<no match>")?
-                )
-                .into());
-            }
+/// A truncated, single-line rendering of a record, for diagnostics
+fn snippet(record: &str) -> String {
+    const MAX_CHARS: usize = 120;
+    let mut out = String::new();
+    for (i, c) in record.trim().chars().enumerate() {
+        if i == MAX_CHARS {
+            out.push_str("...");
+            break;
+        }
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
         }
     }
+    out
+}
 
-    Ok((tail, out_attrs))
+/// Maps byte offsets in an input buffer to locations. Offsets are expected to be
+/// mostly increasing, so lines are counted incrementally.
+struct LineCounter<'a> {
+    path: &'a Path,
+    buf: &'a str,
+    /// The offset of 'buf' in the input
+    base: usize,
+    offset: usize,
+    line: usize,
+    line_start: usize,
 }
 
-/// Heuristically try to get a reasonably-typed attr val from this
-fn string_to_attr_val(s: &str) -> AttrVal {
-    if s.contains('.') {
-        if let Ok(f) = s.parse::<f64>() {
-            return AttrVal::Float(f.into());
+impl<'a> LineCounter<'a> {
+    fn new(path: &'a Path, buf: &'a str, base: usize) -> Self {
+        Self {
+            path,
+            buf,
+            base,
+            offset: 0,
+            line: 1,
+            line_start: 0,
         }
     }
 
-    if let Ok(i) = s.parse::<i128>() {
-        return BigInt::new_attr_val(i);
-    }
+    fn location(&mut self, offset: usize) -> Location {
+        if offset < self.offset {
+            self.offset = 0;
+            self.line = 1;
+            self.line_start = 0;
+        }
+        for (i, b) in self.buf.as_bytes()[self.offset..offset].iter().enumerate() {
+            if *b == b'\n' {
+                self.line += 1;
+                self.line_start = self.offset + i + 1;
+            }
+        }
+        self.offset = offset;
 
-    AttrVal::String(s.to_string().into())
+        Location {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            column: offset - self.line_start + 1,
+            offset: self.base + offset,
+        }
+    }
 }
 
-struct ReadyToSendEvent {
-    timeline_id: TimelineId,
-    timeline_kvs: Vec<(AttrKey, AttrVal)>,
-    event_kvs: Vec<(AttrKey, AttrVal)>,
+/// Handles records which can't be parsed or mapped. Normally these fail the import, but in
+/// lenient mode they are skipped and written (with their location) to the dead-letter file,
+/// until the maximum error count is exceeded.
+struct DeadLetters {
+    lenient: bool,
+    max_errors: Option<u64>,
+    num_errors: u64,
+    file: Option<BufWriter<File>>,
 }
 
-fn prepare_json_object(
-    val: &serde_json::Value,
-    extra_kvs: &[(AttrKey, AttrVal)],
-    cfg: &PluginConfig,
-    known_timelines: &mut HashMap<
-        (AttrKey, AttrVal),
-        TimelineId,
-        std::hash::BuildHasherDefault<fxhash::FxHasher>,
-    >,
-) -> Result<ReadyToSendEvent, Box<dyn std::error::Error>> {
-    let Some(obj) = val.as_object() else {
-        return Err("Expected JSON object at top level, or in array.".into());
-    };
+impl DeadLetters {
+    /// A resumed import appends to the dead letter file, which has the earlier run's records
+    fn new(cfg: &PluginConfig, resume: bool) -> Result<Self, std::io::Error> {
+        let file = match &cfg.dead_letter_file {
+            Some(path) if cfg.lenient => Some(BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(resume)
+                    .truncate(!resume)
+                    .open(path)?,
+            )),
+            _ => None,
+        };
+
+        Ok(Self {
+            lenient: cfg.lenient,
+            max_errors: cfg.max_errors,
+            num_errors: 0,
+            file,
+        })
+    }
 
-    let mut all_kvs = extra_kvs.to_vec();
-    walk_obj(obj, |key_path, val| {
-        let key = AttrKey::new(key_path.join("."));
-        if let Some(val) = json_leaf_to_attr_val(val) {
-            all_kvs.push((key, val));
+    fn record(&mut self, error: Error, location: Location, record: &str) -> Result<(), Error> {
+        let snippet = snippet(record);
+        if !self.lenient {
+            return Err(Error::Located {
+                location,
+                snippet,
+                error: Box::new(error),
+            });
         }
-    });
 
-    let mut timeline_kvs = vec![];
-    let mut event_kvs = vec![];
-    for (key, val) in all_kvs.into_iter() {
-        if cfg.timeline_names.iter().any(|s| s == key.as_ref())
-            || cfg.timeline_attrs.iter().any(|s| s == key.as_ref())
-        {
-            timeline_kvs.push((key, val));
-        } else {
-            event_kvs.push((key, val));
+        self.num_errors += 1;
+        warn!("Skipping record at {location}. {error}\n    {snippet}");
+
+        if let Some(f) = &mut self.file {
+            let dead_letter = serde_json::json!({
+                "path": location.path,
+                "line": location.line,
+                "column": location.column,
+                "offset": location.offset,
+                "error": error.to_string(),
+                "record": record,
+            });
+            serde_json::to_writer(&mut *f, &dead_letter).map_err(std::io::Error::from)?;
+            f.write_all(b"\n")?;
+        }
+
+        if let Some(max_errors) = self.max_errors {
+            if self.num_errors > max_errors {
+                return Err(Error::MaxErrorsExceeded {
+                    max_errors,
+                    last: Box::new(Error::Located {
+                        location,
+                        snippet,
+                        error: Box::new(error),
+                    }),
+                });
+            }
         }
-    }
 
-    let mut timeline_name_sig = None;
-    let mut timeline_name = cfg.timeline_name_prefix.clone().unwrap_or_default();
+        Ok(())
+    }
 
-    for name_key in cfg.timeline_names.iter() {
-        if let Some((k, v)) = timeline_kvs.iter().find(|(k, _)| k.as_ref() == name_key) {
-            timeline_name_sig = Some((k.clone(), v.clone()));
-            timeline_name += &v.to_string();
-            break;
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        if let Some(f) = &mut self.file {
+            f.flush()?;
         }
+        Ok(())
     }
+}
 
-    let timeline_name_sig = timeline_name_sig.ok_or(
-        "Could not determine timeline name and identity for event. \
-         Make sure 'timeline-name' is given, and at least one choice is present for each input event."
-    )?;
+/// Something read from the input, waiting to be turned into an event
+enum PendingRecord<'p, 's> {
+    Json(JsonValue<'s>),
+    /// A json object which followed a text prefix, with the attrs extracted from the prefix
+    PrefixedJson(Vec<(AttrKey, AttrVal)>, JsonValue<'s>),
+    /// Attrs extracted from a non-json line, its mapping config, and the line itself
+    LineEvent(Vec<(AttrKey, AttrVal)>, &'p PluginConfig, &'s str),
+}
 
-    if !timeline_name.is_empty() {
-        timeline_kvs.push((AttrKey::new("name".into()), timeline_name.into()));
+impl<'p, 's> PendingRecord<'p, 's> {
+    /// The record's text, for diagnostics
+    fn text(&self) -> Cow<'s, str> {
+        match self {
+            PendingRecord::Json(val) | PendingRecord::PrefixedJson(_, val) => {
+                Cow::Owned(val.to_string())
+            }
+            PendingRecord::LineEvent(_, _, line) => Cow::Borrowed(*line),
+        }
     }
+}
+
+/// How many results a worker collects before sending them to the import
+const PARSED_BATCH_LEN: usize = 1024;
+
+/// How many batches of results each worker can get ahead of the import
+const PARSED_CHANNEL_CAPACITY: usize = 16;
+
+/// Something a worker produced from its input, sent in input order
+enum Parsed {
+    Event(MappedEvent),
+    /// A record which couldn't be parsed or mapped
+    Failed {
+        error: Error,
+        location: Location,
+        record: String,
+    },
+    /// A non-json line which was skipped
+    Filtered,
+    /// The input has been consumed up to 'offset', reading 'records' more records.
+    /// The import could be resumed from here if 'resumable' is set; it isn't
+    /// while attrs from non-json lines are still waiting for a json object.
+    Consumed {
+        offset: usize,
+        records: u64,
+        resumable: bool,
+    },
+    /// The whole input (or chunk) has been consumed, up to 'end'. 'lines' is the
+    /// number of newlines in it, including any part skipped when resuming.
+    Finished {
+        end: usize,
+        lines: usize,
+        dangling_attrs: bool,
+        /// The worker's parse job, released once the import has consumed all of its results
+        _job: OwnedSemaphorePermit,
+    },
+    /// The input couldn't be read
+    Fatal(Error),
+}
 
-    let timeline_id = known_timelines
-        .entry(timeline_name_sig)
-        .or_insert_with(TimelineId::allocate);
+/// The parsers used by all of the workers
+struct InputParsers {
+    non_json: NonJsonLineParser,
+    json_prefix: Option<JsonPrefix>,
+}
 
-    let mut event_name = cfg.event_name_prefix.clone().unwrap_or_default();
-    for name_key in cfg.event_names.iter() {
-        if let Some((_k, v)) = event_kvs.iter().find(|(k, _)| k.as_ref() == name_key) {
-            event_name += &v.to_string();
-            break;
+/// Start a worker for each input, running at most 'parse_jobs' at a time. Workers are
+/// started in input order, so the input being imported always has a running worker.
+/// A worker's job isn't released until the import has consumed its results, which bounds
+/// how far the workers read ahead of the import.
+fn spawn_workers(
+    workers: Vec<InputWorker>,
+    parsers: Arc<InputParsers>,
+    interruptor: Interruptor,
+    parse_jobs: usize,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let permits = Arc::new(Semaphore::new(parse_jobs.max(1)));
+        for worker in workers {
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                return;
+            };
+            let parsers = parsers.clone();
+            let interruptor = interruptor.clone();
+            tokio::task::spawn_blocking(move || worker.run(&parsers, &interruptor, permit));
         }
-    }
+    })
+}
 
-    if event_name.is_empty() {
-        return Err("Could not determine event name.\
-                    Make sure 'event-name' is given, and at least one cohice is present for each input event.".into());
+/// The default size of the chunks ndjson inputs are split into
+const DEFAULT_NDJSON_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A part of a memory-mapped input which ends at a line boundary
+struct InputChunk {
+    map: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+/// Memory-map a whole input, as a single chunk
+fn map_input(path: &Path) -> Result<InputChunk, Error> {
+    let read_error = |source| Error::ReadInput {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = File::open(path).map_err(read_error)?;
+    // Safety: the input must not be modified or truncated while it's being imported
+    let map = unsafe { Mmap::map(&file) }.map_err(read_error)?;
+    let end = map.len();
+    Ok(InputChunk {
+        map: Arc::new(map),
+        start: 0,
+        end,
+    })
+}
+
+/// Memory-map an ndjson input, and split it into chunks of about 'chunk_size' bytes, after
+/// 'start_offset'. The first chunk starts at the beginning of the input, so its lines can be
+/// counted.
+fn map_chunks(
+    path: &Path,
+    start_offset: usize,
+    chunk_size: usize,
+) -> Result<Vec<InputChunk>, Error> {
+    let InputChunk { map, .. } = map_input(path)?;
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut end = start_offset;
+    while end + chunk_size < map.len() {
+        let Some(i) = memchr::memchr(b'\n', &map[end + chunk_size..]) else {
+            break;
+        };
+        end += chunk_size + i + 1;
+        chunks.push(InputChunk {
+            map: map.clone(),
+            start,
+            end,
+        });
+        start = end;
     }
-    event_kvs.push((AttrKey::new("name".into()), event_name.into()));
+    let end = map.len();
+    chunks.push(InputChunk { map, start, end });
+    Ok(chunks)
+}
+
+/// Parses and maps a single input, or one chunk of it, sending the results to the import
+struct InputWorker {
+    path: PathBuf,
+    /// The chunk to parse, or None to map and parse the whole input
+    chunk: Option<InputChunk>,
+    /// Where to start parsing, from the start of the input
+    start_offset: usize,
+    mapper: JsonEventMapper,
+    tx: mpsc::Sender<Vec<Parsed>>,
+}
 
-    if let Some(ta) = &cfg.timestamp_attr {
-        if let Some((_, val)) = event_kvs.iter().find(|(k, _)| ta == k.as_ref()) {
-            let units = cfg.timestamp_attr_units.unwrap_or_default();
-            let val = units.attr_val_to_ns(val)?;
-            event_kvs.push((AttrKey::new("timestamp".into()), val.clone()));
+impl InputWorker {
+    fn run(mut self, parsers: &InputParsers, interruptor: &Interruptor, job: OwnedSemaphorePermit) {
+        if let Err(e) = self.parse(parsers, interruptor, job) {
+            // If this fails, the import has already stopped
+            let _ = self.tx.blocking_send(vec![Parsed::Fatal(e)]);
         }
     }
 
-    let rts = ReadyToSendEvent {
-        timeline_id: *timeline_id,
-        timeline_kvs,
-        event_kvs,
-    };
+    fn parse(
+        &mut self,
+        parsers: &InputParsers,
+        interruptor: &Interruptor,
+        job: OwnedSemaphorePermit,
+    ) -> Result<(), Error> {
+        let p = &self.path;
+        let whole_input;
+        let chunk = match &self.chunk {
+            Some(chunk) => chunk,
+            None => {
+                whole_input = map_input(p)?;
+                &whole_input
+            }
+        };
+        let base = chunk.start;
+        let buf = std::str::from_utf8(&chunk.map[chunk.start..chunk.end]).map_err(|e| {
+            Error::ReadInput {
+                path: p.clone(),
+                source: std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            }
+        })?;
+        let mut s =
+            buf.get(self.start_offset - base..)
+                .ok_or_else(|| Error::InvalidResumeOffset {
+                    path: p.clone(),
+                    offset: self.start_offset,
+                })?;
+        let mut lines = LineCounter::new(p, buf, base);
 
-    Ok(rts)
-}
-
-fn json_leaf_to_attr_val(val: &serde_json::Value) -> Option<AttrVal> {
-    match val {
-        // We never call this function with an array or object
-        serde_json::Value::Array(_) => None,
-        serde_json::Value::Object(_) => None,
-        serde_json::Value::Null => None,
-        serde_json::Value::Bool(b) => Some(AttrVal::Bool(*b)),
-        serde_json::Value::Number(n) => {
-            if let Some(i) = n.as_i64() {
-                Some(AttrVal::Integer(i))
-            } else if let Some(u) = n.as_u64() {
-                Some(BigInt::new_attr_val(u as i128))
-            } else if let Some(f) = n.as_f64() {
-                Some(AttrVal::Float(f.into()))
-            } else {
-                // There are just three variants of `Number` in serde-json, and they're handled above.
-                unreachable!()
+        let mut extra_kvs = vec![];
+        let mut pending_records = vec![];
+        let mut out = Vec::with_capacity(PARSED_BATCH_LEN);
+
+        loop {
+            if interruptor.is_set() {
+                return Ok(());
             }
-        }
-        serde_json::Value::String(s) => Some(AttrVal::String(s.clone().into())),
-    }
-}
-
-type JsonPath<'a> = Vec<Cow<'a, str>>;
-
-/// Do a depth-first traversal of a json object. Call 'f' at every leaf value (non-object, non-array).
-fn walk_obj(
-    obj: &serde_json::Map<String, serde_json::Value>,
-    mut f: impl FnMut(&JsonPath, &serde_json::Value),
-) {
-    fn walk_obj_rec(
-        path: &JsonPath,
-        obj: &serde_json::Map<String, serde_json::Value>,
-        f: &mut impl FnMut(&JsonPath, &serde_json::Value),
-    ) {
-        for (k, v) in obj.iter() {
-            let mut path = path.clone();
-            path.push(Cow::Borrowed(k));
-            match v {
-                serde_json::Value::Object(o) => {
-                    walk_obj_rec(&path, o, f);
-                }
-                serde_json::Value::Array(a) => {
-                    walk_array_rec(&path, a, f);
+
+            s = s.trim_start();
+
+            let Some(c) = s.chars().next() else {
+                break;
+            };
+            let offset = buf.len() - s.len();
+            let mut records = 1;
+
+            if c == '[' || c == '{' {
+                match parse_json_values(s) {
+                    Ok((s_prime, vals)) => {
+                        records = vals.len() as u64;
+                        pending_records.extend(vals.into_iter().map(PendingRecord::Json));
+                        s = s_prime;
+                    }
+                    Err(e) => {
+                        // Resync after the broken record: it shouldn't take the following
+                        // record down with it, or fail again on each of its remaining lines
+                        let s_prime = skip_broken_record(buf, offset);
+                        let record_len = s.len() - s_prime.len();
+                        let location =
+                            lines.location(offset + json_error_offset(s, &e).min(record_len));
+                        out.push(Parsed::Failed {
+                            error: e.into(),
+                            location,
+                            record: s[..s.len() - s_prime.len()].to_string(),
+                        });
+                        s = s_prime;
+                    }
                 }
-                _ => {
-                    f(&path, v);
+            } else {
+                let prefixed = match &parsers.json_prefix {
+                    Some(json_prefix) => json_prefix.split(s),
+                    None => Ok(None),
+                };
+                match prefixed {
+                    Ok(Some((prefix_kvs, json))) => match parse_json_values(json) {
+                        Ok((s_prime, vals)) => {
+                            records = vals.len() as u64;
+                            pending_records.extend(
+                                vals.into_iter().map(|val| {
+                                    PendingRecord::PrefixedJson(prefix_kvs.clone(), val)
+                                }),
+                            );
+                            s = s_prime;
+                        }
+                        Err(e) => {
+                            let s_prime = skip_broken_record(buf, offset);
+                            let json_offset = buf.len() - json.len();
+                            let json_len = json.len() - s_prime.len();
+                            let location = lines
+                                .location(json_offset + json_error_offset(json, &e).min(json_len));
+                            out.push(Parsed::Failed {
+                                error: e.into(),
+                                location,
+                                record: s[..s.len() - s_prime.len()].to_string(),
+                            });
+                            s = s_prime;
+                        }
+                    },
+                    Ok(None) => {
+                        let (line, s_prime) = parsers.non_json.take_line(s);
+                        match parsers.non_json.parse_line(line) {
+                            Ok(NonJsonLine::Attrs(kvs)) => extra_kvs.extend(kvs),
+                            Ok(NonJsonLine::Event(kvs, event_cfg)) => {
+                                pending_records.push(PendingRecord::LineEvent(kvs, event_cfg, line))
+                            }
+                            Ok(NonJsonLine::Skip) => out.push(Parsed::Filtered),
+                            Err(e) => out.push(Parsed::Failed {
+                                error: e.into(),
+                                location: lines.location(offset),
+                                record: line.to_string(),
+                            }),
+                        }
+                        s = s_prime;
+                    }
+                    Err(e) => {
+                        let s_prime = skip_broken_record(buf, offset);
+                        out.push(Parsed::Failed {
+                            error: e.into(),
+                            location: lines.location(offset),
+                            record: s[..s.len() - s_prime.len()].to_string(),
+                        });
+                        s = s_prime;
+                    }
                 }
             }
-        }
-    }
 
-    fn walk_array_rec(
-        path: &JsonPath,
-        array: &[serde_json::Value],
-        f: &mut impl FnMut(&JsonPath, &serde_json::Value),
-    ) {
-        for (i, v) in array.iter().enumerate() {
-            let mut path = path.clone();
-            path.push(Cow::Owned(format!("{i}")));
-
-            match v {
-                serde_json::Value::Object(o) => {
-                    walk_obj_rec(&path, o, f);
+            if !pending_records.is_empty() {
+                let consumed_extra_kvs = pending_records
+                    .iter()
+                    .any(|r| matches!(r, PendingRecord::Json(_) | PendingRecord::PrefixedJson(..)));
+
+                for record in pending_records.drain(..) {
+                    let prepared = match &record {
+                        PendingRecord::Json(val) => self.mapper.map_json_value(val, &extra_kvs),
+                        PendingRecord::PrefixedJson(prefix_kvs, val) => {
+                            let mut record_kvs = extra_kvs.clone();
+                            record_kvs.extend(prefix_kvs.iter().cloned());
+                            self.mapper.map_json_value(val, &record_kvs)
+                        }
+                        PendingRecord::LineEvent(kvs, event_cfg, _) => {
+                            self.mapper.map_attrs_with_config(kvs.clone(), event_cfg)
+                        }
+                    };
+
+                    out.push(match prepared {
+                        Ok(rts) => Parsed::Event(rts),
+                        Err(e) => Parsed::Failed {
+                            error: e.into(),
+                            location: lines.location(offset),
+                            record: record.text().into_owned(),
+                        },
+                    });
                 }
-                serde_json::Value::Array(a) => {
-                    walk_array_rec(&path, a, f);
+
+                if consumed_extra_kvs {
+                    extra_kvs.clear();
                 }
-                _ => {
-                    f(&path, v);
+            }
+
+            out.push(Parsed::Consumed {
+                offset: base + buf.len() - s.len(),
+                records,
+                resumable: extra_kvs.is_empty(),
+            });
+            if out.len() >= PARSED_BATCH_LEN {
+                let batch = std::mem::replace(&mut out, Vec::with_capacity(PARSED_BATCH_LEN));
+                if self.tx.blocking_send(batch).is_err() {
+                    // The import has stopped
+                    return Ok(());
                 }
             }
         }
+
+        out.push(Parsed::Finished {
+            end: base + buf.len(),
+            lines: memchr::memchr_iter(b'\n', buf.as_bytes()).count(),
+            dangling_attrs: !extra_kvs.is_empty(),
+            _job: job,
+        });
+        let _ = self.tx.blocking_send(out);
+        Ok(())
     }
+}
 
-    walk_obj_rec(&vec![], obj, &mut f)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modality_json::config::CoercionType;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_attr_key_renames() {
+        assert_eq!(
+            parse_attr_key_rename("event.foo,event.bar").unwrap(),
+            AttrKeyRename {
+                original: "event.foo".to_string(),
+                new: "event.bar".to_string(),
+                kind: RenameKind::Exact,
+            }
+        );
+        assert_eq!(
+            parse_attr_key_rename("regex:event\\.(.*)_ms,event.$1").unwrap(),
+            AttrKeyRename {
+                original: "event\\.(.*)_ms".to_string(),
+                new: "event.$1".to_string(),
+                kind: RenameKind::Regex,
+            }
+        );
+        assert_eq!(
+            parse_attr_key_rename("glob:event.*_ms,event.*").unwrap(),
+            AttrKeyRename {
+                original: "event.*_ms".to_string(),
+                new: "event.*".to_string(),
+                kind: RenameKind::Glob,
+            }
+        );
+        assert!(parse_attr_key_rename("event.foo").is_err());
+    }
+
+    #[test]
+    fn parses_attr_coercions() {
+        assert_eq!(
+            parse_attr_coercion("event.code,hex-int").unwrap(),
+            AttrCoercion {
+                path: "event.code".to_string(),
+                to: CoercionType::HexInt,
+                on_error: CoercionErrorAction::Fail,
+                units: None,
+            }
+        );
+        assert_eq!(
+            parse_attr_coercion("event.count,int,fallback").unwrap(),
+            AttrCoercion {
+                path: "event.count".to_string(),
+                to: CoercionType::Int,
+                on_error: CoercionErrorAction::Fallback,
+                units: None,
+            }
+        );
+        assert!(parse_attr_coercion("event.count").is_err());
+        assert!(parse_attr_coercion("event.count,integer-ish").is_err());
+        assert!(parse_attr_coercion("event.count,int,ignore").is_err());
+    }
 }
@@ -0,0 +1,146 @@
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const BAR_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ProgressMode {
+    /// A progress bar when stderr is a terminal, log lines otherwise
+    #[default]
+    Auto,
+    Bar,
+    Log,
+    None,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "auto" => Ok(ProgressMode::Auto),
+            "bar" => Ok(ProgressMode::Bar),
+            "log" => Ok(ProgressMode::Log),
+            "none" | "off" => Ok(ProgressMode::None),
+            _ => Err(format!("Unknown progress mode {s}")),
+        }
+    }
+}
+
+/// Periodic progress reporting to stderr, for long imports
+pub struct Progress {
+    output: Output,
+    total_bytes: Option<u64>,
+    start: Instant,
+    last_update: Instant,
+    interval: Duration,
+}
+
+enum Output {
+    Bar(ProgressBar),
+    Log,
+    None,
+}
+
+impl Progress {
+    /// 'total_bytes' is the combined size of the inputs, when known
+    pub fn new(mode: ProgressMode, total_bytes: Option<u64>) -> Self {
+        let mode = match mode {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Log,
+            m => m,
+        };
+
+        let (output, interval) = match mode {
+            ProgressMode::Bar => {
+                let bar = match total_bytes {
+                    Some(total) => ProgressBar::new(total).with_style(
+                        ProgressStyle::with_template(
+                            "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta}) {msg}",
+                        )
+                        .unwrap_or_else(|_| ProgressStyle::default_bar()),
+                    ),
+                    None => ProgressBar::new_spinner().with_style(
+                        ProgressStyle::with_template(
+                            "{spinner} [{elapsed_precise}] {bytes} ({bytes_per_sec}) {msg}",
+                        )
+                        .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+                    ),
+                };
+                (Output::Bar(bar), BAR_UPDATE_INTERVAL)
+            }
+            ProgressMode::Log => (Output::Log, DEFAULT_LOG_INTERVAL),
+            ProgressMode::Auto | ProgressMode::None => (Output::None, DEFAULT_LOG_INTERVAL),
+        };
+
+        let now = Instant::now();
+        Self {
+            output,
+            total_bytes,
+            start: now,
+            last_update: now,
+            interval,
+        }
+    }
+
+    /// Report the total bytes and records processed so far. Output is rate limited, so this is
+    /// cheap to call for every record.
+    pub fn update(&mut self, bytes: u64, records: u64) {
+        if matches!(self.output, Output::None) || self.last_update.elapsed() < self.interval {
+            return;
+        }
+        self.last_update = Instant::now();
+
+        match &self.output {
+            Output::Bar(bar) => {
+                bar.set_position(bytes);
+                bar.set_message(format!("{records} records"));
+            }
+            Output::Log => eprintln!("{}", self.log_line(bytes, records)),
+            Output::None => (),
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Output::Bar(bar) = &self.output {
+            bar.finish_and_clear();
+        }
+    }
+
+    fn log_line(&self, bytes: u64, records: u64) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 {
+            bytes as f64 / elapsed
+        } else {
+            0.0
+        };
+        let records_per_sec = if elapsed > 0.0 {
+            records as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let mut line = format!("Processed {}", HumanBytes(bytes));
+        if let Some(total) = self.total_bytes.filter(|t| *t > 0) {
+            line += &format!(
+                " of {} ({:.1}%)",
+                HumanBytes(total),
+                bytes as f64 * 100.0 / total as f64
+            );
+        }
+        line += &format!(
+            ", {records} records ({}/s, {records_per_sec:.0} records/s)",
+            HumanBytes(bytes_per_sec as u64)
+        );
+        if let Some(total) = self.total_bytes {
+            if bytes_per_sec > 0.0 && total > bytes {
+                let eta = Duration::from_secs_f64((total - bytes) as f64 / bytes_per_sec);
+                line += &format!(", ETA {}", HumanDuration(eta));
+            }
+        }
+        line
+    }
+}
@@ -0,0 +1,92 @@
+use clap::Parser;
+use modality_json::connection::{IngestConnector, TcpIngestConnector};
+use modality_json::record::replay;
+use modality_json::{prelude::*, tracing::try_init_tracing_subscriber};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Send an ingest stream recorded by the importer's --record option to modality
+#[derive(Parser, Debug, Clone)]
+#[clap(version)]
+pub struct Opts {
+    #[clap(flatten)]
+    pub rf_opts: ReflectorOpts,
+
+    /// The recording to replay
+    #[clap(name = "recording")]
+    pub recording: PathBuf,
+}
+
+#[derive(Debug, Error)]
+enum Error {
+    #[error("Failed to initialize tracing. {0}")]
+    Tracing(#[from] tracing_subscriber::util::TryInitError),
+
+    #[error("Failed to load the configuration. {0}")]
+    Config(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Failed to open the recording '{path}'. {source}")]
+    OpenRecording {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+
+    #[error(transparent)]
+    Auth(#[from] modality_json::auth::AuthTokenError),
+
+    #[error(transparent)]
+    Plugin(#[from] modality_json::error::Error),
+}
+
+#[tokio::main]
+async fn main() {
+    match do_main().await {
+        Ok(()) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            let mut cause = std::error::Error::source(&e);
+            while let Some(err) = cause {
+                eprintln!("Caused by: {err}");
+                cause = err.source();
+            }
+            std::process::exit(exitcode::SOFTWARE);
+        }
+    }
+}
+
+async fn do_main() -> Result<(), Error> {
+    let opts = Opts::parse();
+
+    try_init_tracing_subscriber()?;
+
+    let cfg = JsonConfig::load_merge_with_opts(opts.rf_opts).map_err(Error::Config)?;
+
+    let f = File::open(&opts.recording).map_err(|source| Error::OpenRecording {
+        path: opts.recording.clone(),
+        source,
+    })?;
+
+    let connector = TcpIngestConnector {
+        url: cfg.protocol_parent_url()?,
+        allow_insecure_tls: cfg.ingest.allow_insecure_tls,
+        timeout: Duration::from_secs(cfg.plugin.timeout_seconds.unwrap_or(1)),
+        auth_token: cfg.resolve_auth()?.into(),
+    };
+    let mut conn = connector.connect().await?;
+
+    let stats = replay(BufReader::new(f), conn.as_mut()).await?;
+    eprintln!(
+        "Replayed {} events on {} timelines from '{}'",
+        stats.events,
+        stats.timelines,
+        opts.recording.display()
+    );
+
+    Ok(())
+}
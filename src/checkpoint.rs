@@ -0,0 +1,90 @@
+use crate::types::StoredAttrVal;
+use modality_api::{AttrKey, AttrVal, TimelineId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// The progress of an import, saved to a state file so that an interrupted
+/// import can be resumed where it stopped
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct ImportState {
+    pub run_id: Option<Uuid>,
+    pub inputs: BTreeMap<PathBuf, InputState>,
+    pub timelines: Vec<TimelineState>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct InputState {
+    /// The byte offset of the first record which hasn't been imported
+    pub offset: usize,
+    /// The ordering counter for the next event on each of this input's timelines
+    pub orderings: BTreeMap<Uuid, u128>,
+    /// The input file as it was when it was checkpointed
+    pub fingerprint: Option<InputFingerprint>,
+    /// The whole input has been imported
+    pub complete: bool,
+}
+
+/// The size and modification time of an input, so that a resumed import can tell
+/// whether the input has changed since it was checkpointed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InputFingerprint {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl InputFingerprint {
+    pub fn of(path: &Path) -> Result<Self, std::io::Error> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// A timeline's identity: the timeline name attr which selected it, and the
+/// timeline id it was given
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimelineState {
+    pub name_key: String,
+    pub name_value: StoredAttrVal,
+    pub timeline_id: Uuid,
+}
+
+impl TimelineState {
+    pub fn new(name_key: &AttrKey, name_value: &AttrVal, timeline_id: &TimelineId) -> Self {
+        Self {
+            name_key: name_key.to_string(),
+            name_value: name_value.into(),
+            timeline_id: *timeline_id.get_raw(),
+        }
+    }
+
+    pub fn into_parts(self) -> ((AttrKey, AttrVal), TimelineId) {
+        (
+            (AttrKey::new(self.name_key), self.name_value.into()),
+            TimelineId::from(self.timeline_id),
+        )
+    }
+}
+
+impl ImportState {
+    pub fn load(path: &Path) -> Result<Self, std::io::Error> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Write the state file atomically, by writing to a temporary file and renaming it
+    pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, path)
+    }
+}
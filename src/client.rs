@@ -1,136 +1,307 @@
-use crate::config::AttrKeyRename;
+use crate::connection::{IngestConnection, IngestConnector, RetryPolicy};
 use crate::error::Error;
+use crate::rename::normalize_key;
+use crate::sink::EventSink;
+use crate::types::Interruptor;
+use async_trait::async_trait;
 use modality_api::{AttrKey, AttrVal, TimelineId};
-use modality_ingest_client::dynamic::DynamicIngestClient;
 use modality_ingest_client::{IngestClient, ReadyState};
 use modality_ingest_protocol::InternedAttrKey;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::warn;
 
+/// The default number of events to buffer before sending them
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// How many sent batches are kept, so they can be replayed, before the connection is flushed
+const UNFLUSHED_BATCHES: usize = 16;
+
+/// Buffers events, grouped by timeline, and sends them in batches: all the new attr keys in a
+/// batch are declared up front, each timeline is opened once per batch, and its metadata
+/// changes are coalesced into a single update. Attr keys are given their 'timeline.' or
+/// 'event.' prefix; renames are done by a [`RenamingSink`](crate::sink::RenamingSink) in
+/// front of the client.
+///
+/// Sent batches are kept until the connection is flushed, which happens every few batches
+/// and whenever the client is flushed. If a connector is configured with
+/// [`Client::with_reconnect`], connection failures are retried: the client reconnects with
+/// backoff, and replays every unflushed batch on the new connection, re-declaring their attr
+/// keys and re-sending the metadata of their timelines.
+///
+/// Delivery is at-least-once: there's no way to tell how much of what was sent on a failed
+/// connection arrived, so events sent since the last flush may be received twice.
 pub struct Client {
-    pub c: DynamicIngestClient,
+    conn: Box<dyn IngestConnection>,
+    connector: Option<Box<dyn IngestConnector>>,
+    retry_policy: RetryPolicy,
+    interruptor: Option<Interruptor>,
     timeline_keys: BTreeMap<String, InternedAttrKey>,
     event_keys: BTreeMap<String, InternedAttrKey>,
-    rename_timeline_attrs: HashMap<String, String>,
-    rename_event_attrs: HashMap<String, String>,
-    sent_timeline_attrs: HashMap<(TimelineId, InternedAttrKey), AttrVal>,
+    sent_timeline_attrs: HashMap<TimelineId, HashMap<String, AttrVal>>,
+    /// Timelines whose metadata has been sent on the current connection
+    connection_timelines: HashSet<TimelineId>,
     current_timeline: Option<TimelineId>,
+    batch_size: usize,
+    batch: Vec<TimelineBatch>,
+    batch_index: HashMap<TimelineId, usize>,
+    batch_events: usize,
+    /// Batches which have been sent (or are being sent) since the connection was last flushed
+    unflushed: Vec<Vec<TimelineBatch>>,
+    /// How many of the unflushed batches have been sent on the current connection
+    unflushed_sent: usize,
 }
 
-fn normalize_timeline_key(s: String) -> String {
-    if s.starts_with("timeline.") {
-        s
-    } else {
-        format!("timeline.{s}")
-    }
+/// The buffered messages for one timeline, with the attr keys already prefixed
+struct TimelineBatch {
+    timeline_id: TimelineId,
+    metadata: BTreeMap<String, AttrVal>,
+    events: Vec<(u128, Vec<(String, AttrVal)>)>,
 }
 
-fn normalize_event_key(s: String) -> String {
-    if s.starts_with("event.") {
-        s
-    } else {
-        format!("event.{s}")
+impl Client {
+    pub fn new(c: IngestClient<ReadyState>, batch_size: usize) -> Self {
+        let c: modality_ingest_client::dynamic::DynamicIngestClient = c.into();
+        Self::with_connection(Box::new(c), batch_size)
     }
-}
 
-impl Client {
-    pub fn new(
-        c: IngestClient<ReadyState>,
-        rename_timeline_attrs: Vec<AttrKeyRename>,
-        rename_event_attrs: Vec<AttrKeyRename>,
-    ) -> Self {
+    pub fn with_connection(conn: Box<dyn IngestConnection>, batch_size: usize) -> Self {
         Self {
-            c: c.into(),
+            conn,
+            connector: None,
+            retry_policy: RetryPolicy::default(),
+            interruptor: None,
             timeline_keys: Default::default(),
             event_keys: Default::default(),
-            rename_timeline_attrs: rename_timeline_attrs
-                .into_iter()
-                .map(|r| {
-                    (
-                        normalize_timeline_key(r.original),
-                        normalize_timeline_key(r.new),
-                    )
-                })
-                .collect(),
-            rename_event_attrs: rename_event_attrs
-                .into_iter()
-                .map(|r| (normalize_event_key(r.original), normalize_event_key(r.new)))
-                .collect(),
             sent_timeline_attrs: HashMap::new(),
+            connection_timelines: HashSet::new(),
             current_timeline: None,
+            batch_size: batch_size.max(1),
+            batch: Vec::new(),
+            batch_index: HashMap::new(),
+            batch_events: 0,
+            unflushed: Vec::new(),
+            unflushed_sent: 0,
         }
     }
 
-    pub async fn send_event_on_timeline(
-        &mut self,
-        timeline_id: TimelineId,
-        timeline_kvs: Vec<(AttrKey, AttrVal)>,
-        ordering: u128,
-        event_kvs: Vec<(AttrKey, AttrVal)>,
-    ) -> Result<(), Error> {
-        if self.current_timeline != Some(timeline_id) {
-            self.c.open_timeline(timeline_id).await?;
-            self.current_timeline = Some(timeline_id);
+    /// Reconnect using the given connector when the connection fails
+    pub fn with_reconnect(
+        mut self,
+        connector: Box<dyn IngestConnector>,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        self.connector = Some(connector);
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Give up reconnecting when the given interruptor is set
+    pub fn with_interruptor(mut self, interruptor: Interruptor) -> Self {
+        self.interruptor = Some(interruptor);
+        self
+    }
+
+    /// Send the buffered batch, and flush the connection if 'flush' is set or enough
+    /// batches are waiting for a flush
+    async fn send_batch(&mut self, flush: bool) -> Result<(), Error> {
+        if !self.batch.is_empty() {
+            self.unflushed.push(std::mem::take(&mut self.batch));
+            self.batch_index.clear();
+            self.batch_events = 0;
         }
+        let flush = flush || self.unflushed.len() >= UNFLUSHED_BATCHES;
 
-        for (tk, tv) in timeline_kvs.into_iter() {
-            let itk = self.interned_timeline_key(tk).await?;
-            match self.sent_timeline_attrs.entry((timeline_id, itk)) {
-                std::collections::hash_map::Entry::Occupied(mut ocupado) => {
-                    if ocupado.get() != &tv {
-                        self.c
-                            .timeline_metadata(std::iter::once((itk, tv.clone())))
-                            .await?;
-                        ocupado.insert(tv);
+        let mut attempt = 0;
+        while let Err(mut err) = self.try_send_unflushed(flush).await {
+            loop {
+                let connector = match &self.connector {
+                    Some(connector)
+                        if err.is_connection_error()
+                            && attempt < self.retry_policy.max_attempts =>
+                    {
+                        connector
+                    }
+                    _ => return Err(err),
+                };
+
+                let delay = self.retry_policy.backoff(attempt);
+                attempt += 1;
+                warn!(
+                    "Ingest connection failed, reconnecting in {delay:?} (attempt {attempt}/{}). {err}",
+                    self.retry_policy.max_attempts
+                );
+                if let Some(interruptor) = &self.interruptor {
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => (),
+                        _ = interruptor.interrupted() => return Err(err),
                     }
+                } else {
+                    tokio::time::sleep(delay).await;
                 }
-                std::collections::hash_map::Entry::Vacant(vaca) => {
-                    self.c
-                        .timeline_metadata(std::iter::once((itk, tv.clone())))
-                        .await?;
-                    vaca.insert(tv);
+
+                let connected = connector.connect().await;
+                match connected {
+                    Ok(conn) => {
+                        self.reset_connection(conn);
+                        break;
+                    }
+                    Err(e) => err = e,
                 }
             }
         }
 
-        let mut interned_event_kvs = vec![];
-        for (ek, ev) in event_kvs.into_iter() {
-            interned_event_kvs.push((self.interned_event_key(ek).await?, ev));
-        }
+        Ok(())
+    }
 
-        self.c.event(ordering, interned_event_kvs).await?;
+    /// Send the unflushed batches which haven't been sent on this connection yet
+    async fn try_send_unflushed(&mut self, flush: bool) -> Result<(), Error> {
+        while self.unflushed_sent < self.unflushed.len() {
+            let batch = std::mem::take(&mut self.unflushed[self.unflushed_sent]);
+            let sent = self.try_send_batch(&batch).await;
+            self.unflushed[self.unflushed_sent] = batch;
+            sent?;
+            self.unflushed_sent += 1;
+        }
 
+        if flush {
+            self.conn.flush().await?;
+            self.unflushed.clear();
+            self.unflushed_sent = 0;
+        }
         Ok(())
     }
 
-    async fn interned_timeline_key(&mut self, key: AttrKey) -> Result<InternedAttrKey, Error> {
-        let mut key = &normalize_timeline_key(key.to_string());
-        if let Some(new) = self.rename_timeline_attrs.get(key) {
-            key = new;
+    /// Start over with a new connection, which hasn't seen any keys or timelines
+    fn reset_connection(&mut self, conn: Box<dyn IngestConnection>) {
+        self.conn = conn;
+        self.timeline_keys.clear();
+        self.event_keys.clear();
+        self.connection_timelines.clear();
+        self.current_timeline = None;
+        self.unflushed_sent = 0;
+    }
+
+    async fn try_send_batch(&mut self, batch: &[TimelineBatch]) -> Result<(), Error> {
+        // Timelines which are new to this connection get all of their metadata
+        let metadata: Vec<BTreeMap<&String, &AttrVal>> = batch
+            .iter()
+            .map(|tb| {
+                let mut md = BTreeMap::new();
+                if !self.connection_timelines.contains(&tb.timeline_id) {
+                    if let Some(sent) = self.sent_timeline_attrs.get(&tb.timeline_id) {
+                        md.extend(sent.iter());
+                    }
+                }
+                md.extend(tb.metadata.iter());
+                md
+            })
+            .collect();
+
+        // Declare all of the batch's new keys before sending anything else
+        for tk in metadata.iter().flat_map(|md| md.keys()) {
+            if !self.timeline_keys.contains_key(*tk) {
+                let k = self.conn.declare_attr_key((*tk).clone()).await?;
+                self.timeline_keys.insert((*tk).clone(), k);
+            }
+        }
+        for (ek, _) in batch
+            .iter()
+            .flat_map(|tb| tb.events.iter())
+            .flat_map(|(_, kvs)| kvs.iter())
+        {
+            if !self.event_keys.contains_key(ek) {
+                let k = self.conn.declare_attr_key(ek.clone()).await?;
+                self.event_keys.insert(ek.clone(), k);
+            }
+        }
+
+        for (tb, md) in batch.iter().zip(metadata.iter()) {
+            if self.current_timeline != Some(tb.timeline_id) {
+                self.conn.open_timeline(tb.timeline_id).await?;
+                self.current_timeline = Some(tb.timeline_id);
+            }
+
+            if !md.is_empty() {
+                let interned_metadata = md
+                    .iter()
+                    .map(|(k, v)| (self.timeline_keys[*k], (*v).clone()))
+                    .collect();
+                self.conn.timeline_metadata(interned_metadata).await?;
+            }
+            self.connection_timelines.insert(tb.timeline_id);
+
+            for (ordering, event_kvs) in tb.events.iter() {
+                let interned_event_kvs = event_kvs
+                    .iter()
+                    .map(|(k, v)| (self.event_keys[k], v.clone()))
+                    .collect();
+                self.conn.event(*ordering, interned_event_kvs).await?;
+            }
+        }
+
+        for tb in batch.iter() {
+            self.sent_timeline_attrs
+                .entry(tb.timeline_id)
+                .or_default()
+                .extend(tb.metadata.iter().map(|(k, v)| (k.clone(), v.clone())));
         }
 
-        let int_key = if let Some(k) = self.timeline_keys.get(key) {
-            *k
-        } else {
-            let k = self.c.declare_attr_key(key.to_string()).await?;
-            self.timeline_keys.insert(key.to_string(), k);
-            k
-        };
-        Ok(int_key)
+        Ok(())
     }
+}
+
+#[async_trait]
+impl EventSink for Client {
+    /// Buffer an event, sending the batch if it's full
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        let idx = *self.batch_index.entry(timeline_id).or_insert_with(|| {
+            self.batch.push(TimelineBatch {
+                timeline_id,
+                metadata: Default::default(),
+                events: Vec::new(),
+            });
+            self.batch.len() - 1
+        });
+        let sent = self.sent_timeline_attrs.get(&timeline_id);
+        let tb = &mut self.batch[idx];
+
+        for (tk, tv) in timeline_kvs.into_iter() {
+            let tk = normalize_key("timeline.", tk.to_string());
+            let current = tb
+                .metadata
+                .get(&tk)
+                .or_else(|| sent.and_then(|s| s.get(&tk)));
+            if current != Some(&tv) {
+                tb.metadata.insert(tk, tv);
+            }
+        }
 
-    async fn interned_event_key(&mut self, key: AttrKey) -> Result<InternedAttrKey, Error> {
-        let mut key = &normalize_event_key(key.to_string());
-        if let Some(new) = self.rename_event_attrs.get(key) {
-            key = new;
+        let event_kvs = event_kvs
+            .into_iter()
+            .map(|(ek, ev)| (normalize_key("event.", ek.to_string()), ev))
+            .collect();
+        tb.events.push((ordering, event_kvs));
+
+        self.batch_events += 1;
+        if self.batch_events >= self.batch_size {
+            self.send_batch(false).await?;
         }
 
-        let int_key = if let Some(k) = self.event_keys.get(&key.to_string()) {
-            *k
-        } else {
-            let k = self.c.declare_attr_key(key.to_string()).await?;
-            self.event_keys.insert(key.to_string(), k);
-            k
-        };
-        Ok(int_key)
+        Ok(())
+    }
+
+    /// Send the buffered events, and flush the connection
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.send_batch(true).await
+    }
+
+    fn declared_attr_key_count(&self) -> usize {
+        self.timeline_keys.len() + self.event_keys.len()
     }
 }
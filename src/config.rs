@@ -1,11 +1,12 @@
-use modality_api::AttrVal;
+use modality_api::{AttrVal, BigInt, Nanoseconds, TimelineId};
 use modality_reflector_config::{Config, TomlValue, TopLevelIngest, CONFIG_ENV_VAR};
 use serde::Deserialize;
 use std::{
-    env,
+    env, fmt,
     path::{Path, PathBuf},
     str::FromStr,
 };
+use thiserror::Error;
 use url::Url;
 use uuid::Uuid;
 
@@ -28,6 +29,35 @@ pub struct PluginConfig {
 
     pub timeout_seconds: Option<u64>,
 
+    /// The number of events to buffer before sending them to modality. Defaults to 1000.
+    pub ingest_batch_size: Option<usize>,
+
+    /// How many times to try reconnecting when the ingest connection
+    /// fails, with exponential backoff. Defaults to 10.
+    pub reconnect_attempts: Option<u32>,
+
+    /// Write the ingest stream to this file instead of sending it to
+    /// modality, to be replayed later
+    pub record_file: Option<PathBuf>,
+
+    /// Write the mapped events to this file, as json lines, instead
+    /// of sending them to modality
+    pub output_file: Option<PathBuf>,
+
+    /// How many input files to parse and map at the same time.
+    /// Defaults to the number of available CPUs. Each job holds up to
+    /// 16k mapped records which are waiting to be imported.
+    pub parse_jobs: Option<usize>,
+
+    /// Treat the inputs as newline-delimited json, with at most one
+    /// record per line, so that each input can be split into chunks
+    /// which are parsed and mapped in parallel
+    pub ndjson: bool,
+
+    /// The approximate size, in bytes, of the chunks ndjson inputs are
+    /// split into. Defaults to 16 MiB.
+    pub ndjson_chunk_size: Option<u64>,
+
     // TODO this is currently one-attr, with fallbacks. Should it instead be compound?
     /// The json path to the key that will be used to determine the
     /// name of an event. If given multiple times, the paths with be
@@ -57,6 +87,40 @@ pub struct PluginConfig {
     /// Rename an event attribute key as it is being imported
     pub rename_event_attrs: Vec<AttrKeyRename>,
 
+    /// The string used to join the segments of a flattened json key
+    /// path into an attr key. Defaults to '.'.
+    pub key_path_separator: Option<String>,
+
+    /// What to do with characters in a json key segment that aren't
+    /// valid in an attr key (anything other than ascii alphanumerics,
+    /// '_' and '-', including the separator itself). One of keep,
+    /// replace, strip. Defaults to keep.
+    pub key_segment_policy: Option<KeySegmentPolicy>,
+
+    /// The replacement string used by the 'replace' key segment
+    /// policy. Defaults to '_', or '-' if the separator contains '_'.
+    pub key_segment_replacement: Option<String>,
+
+    /// What to do with json null values. One of drop, sentinel,
+    /// is-null, fail. Defaults to drop.
+    pub null_policy: Option<NullPolicy>,
+
+    /// The string value used for nulls by the 'sentinel' null
+    /// policy. Defaults to 'null'.
+    pub null_sentinel: Option<String>,
+
+    /// Coerce the values found at these attr keys (json paths, or
+    /// non-json attr names) to a specific type
+    pub coerce_attrs: Vec<AttrCoercion>,
+
+    /// Drop, mask, or hash sensitive attr values before they are sent
+    pub redact: Vec<RedactionRule>,
+
+    /// The secret key used by the 'hash' redaction action. Can also
+    /// be provided via the MODALITY_JSON_REDACTION_KEY environment
+    /// variable.
+    pub redaction_hash_key: Option<String>,
+
     /// The json path where the event's timestamp can be found
     pub timestamp_attr: Option<String>,
 
@@ -70,9 +134,72 @@ pub struct PluginConfig {
     /// in non-json-regex. These are treated positionally, with
     /// respect to the subgroup's position in the regex. This data is
     /// produced early, so all the other options apply equally to the
-    /// regex-extracted data and to the json-sourced data.
+    /// regex-extracted data and to the json-sourced data. If not
+    /// given, the names of the regex's named capture groups
+    /// ('(?P<name>...)') are used as attr keys.
     pub non_json_attrs: Vec<String>,
 
+    /// If a line starts with text matching this regex, followed by a
+    /// json object, parse the json object and merge the attrs
+    /// extracted from the prefix into it.
+    pub json_prefix_regex: Option<String>,
+
+    /// The attr keys for the capture groups in json-prefix-regex,
+    /// treated positionally. If not given, the names of the regex's
+    /// named capture groups are used.
+    pub json_prefix_attrs: Vec<String>,
+
+    /// Additional rules for parsing lines that don't look like a json
+    /// object. These are tried in order, after non-json-regex (if
+    /// given), and the first rule that matches is used.
+    pub non_json_rules: Vec<NonJsonRule>,
+
+    /// What to do with a non-json line that matches no rule. One of
+    /// skip, warn, fail. Defaults to fail.
+    pub unmatched_line_action: Option<UnmatchedLineAction>,
+
+    /// Group non-json lines into multi-line records: a record starts
+    /// with a line matching this regex, and continues with every
+    /// following line that doesn't match it (and doesn't start a json
+    /// value).
+    pub multiline_start_regex: Option<String>,
+
+    /// Group non-json lines into multi-line records: lines which start
+    /// with whitespace (and don't start a json value) are a continuation
+    /// of the previous line.
+    pub multiline_indent_continuation: bool,
+
+    /// The attr key used for the continuation lines of a multi-line
+    /// record. If not given, the continuation lines are only used for
+    /// regex matching.
+    pub multiline_body_attr: Option<String>,
+
+    /// The maximum number of lines in a multi-line record. Defaults to 1000.
+    pub multiline_max_lines: Option<usize>,
+
+    /// Skip records which can't be parsed or mapped, instead of
+    /// failing the import
+    pub lenient: bool,
+
+    /// In lenient mode, write skipped records (and their location)
+    /// to this file, as json lines
+    pub dead_letter_file: Option<PathBuf>,
+
+    /// Allow a dead letter file when redaction rules are configured.
+    /// Dead letters hold the raw record text, which isn't redacted.
+    pub dead_letter_unredacted: bool,
+
+    /// In lenient mode, fail the import once more than this many
+    /// records have been skipped
+    pub max_errors: Option<u64>,
+
+    /// Record the progress of the import in this file, so that it
+    /// can be resumed if it's interrupted
+    pub state_file: Option<PathBuf>,
+
+    /// Save the import state after this many records. Defaults to 10000.
+    pub checkpoint_interval: Option<u64>,
+
     #[serde(flatten)]
     pub import: ImportConfig,
 }
@@ -85,6 +212,522 @@ pub struct AttrKeyRename {
 
     /// The new attr key name to use
     pub new: String,
+
+    /// How 'original' is matched against attr keys. One of exact,
+    /// regex, glob. Defaults to exact.
+    pub kind: RenameKind,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RenameKind {
+    /// 'original' is the full attr key
+    #[default]
+    Exact,
+    /// 'original' is a regex which must match the whole attr key, and
+    /// 'new' may refer to its capture groups ('$1', '${name}')
+    Regex,
+    /// 'original' is a pattern where '*' matches any sequence of
+    /// characters, and each '*' in 'new' is substituted with the
+    /// corresponding match
+    Glob,
+}
+
+impl FromStr for RenameKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "exact" => Ok(RenameKind::Exact),
+            "regex" => Ok(RenameKind::Regex),
+            "glob" => Ok(RenameKind::Glob),
+            _ => Err(format!("Unknown rename kind {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RenameKind {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KeySegmentPolicy {
+    /// Use the key segment as-is
+    #[default]
+    Keep,
+    /// Replace each invalid character with the configured replacement string
+    Replace,
+    /// Remove invalid characters
+    Strip,
+}
+
+impl KeySegmentPolicy {
+    /// Whether a character may appear in a key segment, which is joined to the others
+    /// with the given separator
+    pub fn is_valid_char(c: char, separator: &str) -> bool {
+        (c.is_ascii_alphanumeric() || c == '_' || c == '-') && !separator.contains(c)
+    }
+}
+
+impl FromStr for KeySegmentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "keep" => Ok(KeySegmentPolicy::Keep),
+            "replace" => Ok(KeySegmentPolicy::Replace),
+            "strip" => Ok(KeySegmentPolicy::Strip),
+            _ => Err(format!("Unknown key segment policy {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySegmentPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum NullPolicy {
+    /// Don't produce an attr for null values
+    #[default]
+    Drop,
+    /// Produce a string attr with the configured sentinel value
+    Sentinel,
+    /// Produce a boolean '<key>.is_null' attr
+    IsNull,
+    /// Fail the record
+    Fail,
+}
+
+impl FromStr for NullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "drop" => Ok(NullPolicy::Drop),
+            "sentinel" => Ok(NullPolicy::Sentinel),
+            "is-null" | "is_null" => Ok(NullPolicy::IsNull),
+            "fail" => Ok(NullPolicy::Fail),
+            _ => Err(format!("Unknown null policy {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NullPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AttrCoercion {
+    /// The attr key whose value should be coerced
+    pub path: String,
+
+    /// The type to coerce the value to
+    pub to: CoercionType,
+
+    /// What to do when the value can't be coerced
+    #[serde(default)]
+    pub on_error: CoercionErrorAction,
+
+    /// The units of the source value, when coercing to a timestamp
+    pub units: Option<TimestampUnit>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CoercionType {
+    String,
+    Int,
+    Float,
+    Bool,
+    /// An integer written in hex, with or without a '0x' prefix
+    HexInt,
+    /// A uuid, normalized to its hyphenated lowercase string form
+    Uuid,
+    /// A numeric timestamp, converted to nanoseconds
+    Timestamp,
+    TimelineId,
+}
+
+/// A value which couldn't be coerced to the requested type
+#[derive(Debug, Error)]
+#[error("Can't coerce value '{value}' to {to}")]
+pub struct CoercionError {
+    pub value: AttrVal,
+    pub to: CoercionType,
+}
+
+impl CoercionType {
+    pub fn coerce(&self, v: &AttrVal, units: TimestampUnit) -> Result<AttrVal, CoercionError> {
+        let err = || CoercionError {
+            value: v.clone(),
+            to: *self,
+        };
+        match self {
+            CoercionType::String => Ok(AttrVal::String(v.to_string().into())),
+            CoercionType::Int => match v {
+                AttrVal::Integer(_) => Ok(v.clone()),
+                AttrVal::BigInt(i) => Ok(BigInt::new_attr_val(*i.as_ref())),
+                AttrVal::Float(f) if f.0.fract() == 0.0 => Ok(BigInt::new_attr_val(f.0 as i128)),
+                AttrVal::Bool(b) => Ok(AttrVal::Integer(*b as i64)),
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    s.trim()
+                        .parse::<i128>()
+                        .map(BigInt::new_attr_val)
+                        .map_err(|_| err())
+                }
+                _ => Err(err()),
+            },
+            CoercionType::Float => match v {
+                AttrVal::Integer(i) => Ok(AttrVal::Float((*i as f64).into())),
+                AttrVal::BigInt(i) => Ok(AttrVal::Float((*i.as_ref() as f64).into())),
+                AttrVal::Float(_) => Ok(v.clone()),
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    s.trim()
+                        .parse::<f64>()
+                        .map(|f| AttrVal::Float(f.into()))
+                        .map_err(|_| err())
+                }
+                _ => Err(err()),
+            },
+            CoercionType::Bool => match v {
+                AttrVal::Bool(_) => Ok(v.clone()),
+                AttrVal::Integer(0) => Ok(AttrVal::Bool(false)),
+                AttrVal::Integer(1) => Ok(AttrVal::Bool(true)),
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    match s.trim().to_lowercase().as_ref() {
+                        "true" | "1" => Ok(AttrVal::Bool(true)),
+                        "false" | "0" => Ok(AttrVal::Bool(false)),
+                        _ => Err(err()),
+                    }
+                }
+                _ => Err(err()),
+            },
+            CoercionType::HexInt => match v {
+                AttrVal::Integer(_) => Ok(v.clone()),
+                AttrVal::BigInt(i) => Ok(BigInt::new_attr_val(*i.as_ref())),
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    let s = s.trim();
+                    let digits = s
+                        .strip_prefix("0x")
+                        .or_else(|| s.strip_prefix("0X"))
+                        .unwrap_or(s);
+                    i128::from_str_radix(digits, 16)
+                        .map(BigInt::new_attr_val)
+                        .map_err(|_| err())
+                }
+                _ => Err(err()),
+            },
+            CoercionType::Uuid => match v {
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    Uuid::parse_str(s.trim())
+                        .map(|u| AttrVal::String(u.hyphenated().to_string().into()))
+                        .map_err(|_| err())
+                }
+                _ => Err(err()),
+            },
+            CoercionType::Timestamp => match v {
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    let f = s.trim().parse::<f64>().map_err(|_| err())?;
+                    units
+                        .attr_val_to_ns(&AttrVal::Float(f.into()))
+                        .map_err(|_| err())
+                }
+                _ => units.attr_val_to_ns(v).map_err(|_| err()),
+            },
+            CoercionType::TimelineId => match v {
+                AttrVal::TimelineId(_) => Ok(v.clone()),
+                AttrVal::String(s) => {
+                    let s: &str = s.as_ref();
+                    Uuid::parse_str(s.trim())
+                        .map(|u| AttrVal::from(TimelineId::from(u)))
+                        .map_err(|_| err())
+                }
+                _ => Err(err()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for CoercionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CoercionType::String => "string",
+            CoercionType::Int => "int",
+            CoercionType::Float => "float",
+            CoercionType::Bool => "bool",
+            CoercionType::HexInt => "hex-int",
+            CoercionType::Uuid => "uuid",
+            CoercionType::Timestamp => "timestamp",
+            CoercionType::TimelineId => "timeline-id",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FromStr for CoercionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "string" | "str" => Ok(CoercionType::String),
+            "int" | "integer" => Ok(CoercionType::Int),
+            "float" => Ok(CoercionType::Float),
+            "bool" | "boolean" => Ok(CoercionType::Bool),
+            "hex-int" | "hex" => Ok(CoercionType::HexInt),
+            "uuid" => Ok(CoercionType::Uuid),
+            "timestamp" => Ok(CoercionType::Timestamp),
+            "timeline-id" => Ok(CoercionType::TimelineId),
+            _ => Err(format!("Unknown coercion type {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CoercionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CoercionErrorAction {
+    /// Fail the record
+    #[default]
+    Fail,
+    /// Keep the original value
+    Fallback,
+}
+
+impl FromStr for CoercionErrorAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "fail" => Ok(CoercionErrorAction::Fail),
+            "fallback" => Ok(CoercionErrorAction::Fallback),
+            _ => Err(format!("Unknown coercion error action {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CoercionErrorAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct RedactionRule {
+    /// Redact the value of this attr key
+    pub path: Option<String>,
+
+    /// Redact the values of attr keys which match this regex
+    pub key_regex: Option<String>,
+
+    /// Only redact the parts of a value which match this regex. If
+    /// the action is 'drop', the whole attr is dropped when any part
+    /// of the value matches.
+    pub value_regex: Option<String>,
+
+    /// What to do with the redacted value
+    pub action: RedactionAction,
+
+    /// The replacement string used by the 'mask' action. Defaults to '****'.
+    pub mask: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RedactionAction {
+    /// Remove the attr entirely
+    Drop,
+    /// Replace the value with a fixed string
+    #[default]
+    Mask,
+    /// Replace the value with a stable pseudonym, derived from a
+    /// keyed hash of the value
+    Hash,
+}
+
+impl FromStr for RedactionAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "drop" => Ok(RedactionAction::Drop),
+            "mask" => Ok(RedactionAction::Mask),
+            "hash" => Ok(RedactionAction::Hash),
+            _ => Err(format!("Unknown redaction action {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RedactionAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct NonJsonRule {
+    /// The regex used to match and parse the line
+    pub regex: String,
+
+    /// The attr keys for the regex's capture groups, treated
+    /// positionally. If not given, the names of the regex's named
+    /// capture groups are used.
+    pub attrs: Vec<String>,
+
+    /// What to do with the data extracted from a matching line
+    pub disposition: LineDisposition,
+
+    /// For the 'event' disposition: overrides the top-level
+    /// event-names for events produced by this rule
+    pub event_names: Vec<String>,
+
+    /// For the 'event' disposition: overrides the top-level
+    /// event-name-prefix for events produced by this rule
+    pub event_name_prefix: Option<String>,
+
+    /// For the 'event' disposition: overrides the top-level
+    /// timeline-names for events produced by this rule
+    pub timeline_names: Vec<String>,
+
+    /// For the 'event' disposition: overrides the top-level
+    /// timeline-name-prefix for events produced by this rule
+    pub timeline_name_prefix: Option<String>,
+
+    /// For the 'event' disposition: overrides the top-level
+    /// timeline-attrs for events produced by this rule
+    pub timeline_attrs: Vec<String>,
+}
+
+impl NonJsonRule {
+    /// The mapping config used for events produced by this rule: the
+    /// given top-level config, with this rule's overrides applied
+    pub fn event_config(&self, cfg: &PluginConfig) -> PluginConfig {
+        let mut event_cfg = cfg.clone();
+        if !self.event_names.is_empty() {
+            event_cfg.event_names = self.event_names.clone();
+        }
+        if self.event_name_prefix.is_some() {
+            event_cfg.event_name_prefix = self.event_name_prefix.clone();
+        }
+        if !self.timeline_names.is_empty() {
+            event_cfg.timeline_names = self.timeline_names.clone();
+        }
+        if self.timeline_name_prefix.is_some() {
+            event_cfg.timeline_name_prefix = self.timeline_name_prefix.clone();
+        }
+        if !self.timeline_attrs.is_empty() {
+            event_cfg.timeline_attrs = self.timeline_attrs.clone();
+        }
+        event_cfg
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LineDisposition {
+    /// Attach the extracted attrs to the next json object
+    #[default]
+    Attach,
+    /// Ignore the line
+    Discard,
+    /// Emit the extracted attrs as an event in their own right
+    Event,
+}
+
+impl FromStr for LineDisposition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "attach" => Ok(LineDisposition::Attach),
+            "discard" => Ok(LineDisposition::Discard),
+            "event" => Ok(LineDisposition::Event),
+            _ => Err(format!("Unknown line disposition {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LineDisposition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum UnmatchedLineAction {
+    /// Silently ignore the line
+    Skip,
+    /// Log a warning and ignore the line
+    Warn,
+    /// Fail the import
+    #[default]
+    Fail,
+}
+
+impl FromStr for UnmatchedLineAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "skip" => Ok(UnmatchedLineAction::Skip),
+            "warn" => Ok(UnmatchedLineAction::Warn),
+            "fail" => Ok(UnmatchedLineAction::Fail),
+            _ => Err(format!("Unknown unmatched line action {s}")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for UnmatchedLineAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Default, Deserialize)]
@@ -102,20 +745,28 @@ pub enum TimestampUnit {
     Nanoseconds,
 }
 
+/// A value which couldn't be converted to a timestamp
+#[derive(Debug, Error)]
+#[error("Found a non-numeric or out of range value in timestamp field: {0}")]
+pub struct TimestampError(pub AttrVal);
+
 impl TimestampUnit {
-    pub fn attr_val_to_ns(
-        &self,
-        v: &modality_api::AttrVal,
-    ) -> Result<AttrVal, Box<dyn std::error::Error>> {
+    /// Convert a number in these units to a timestamp
+    pub fn attr_val_to_ns(&self, v: &modality_api::AttrVal) -> Result<AttrVal, TimestampError> {
         let float_val = match v {
+            // Already in nanoseconds, e.g. from a timestamp coercion
+            AttrVal::Timestamp(_) => return Ok(v.clone()),
             AttrVal::Integer(i) => *i as f64,
             AttrVal::BigInt(i) => *i.as_ref() as f64,
             AttrVal::Float(of) => of.0,
-            _ => return Err(format!("Found non-numeric value in timestamp field: {v}").into()),
+            _ => return Err(TimestampError(v.clone())),
         };
 
         let float_ns = float_val * self.to_ns_factor();
-        Ok(modality_api::BigInt::new_attr_val(float_ns as i128))
+        if !(0.0..=u64::MAX as f64).contains(&float_ns) {
+            return Err(TimestampError(v.clone()));
+        }
+        Ok(AttrVal::from(Nanoseconds::from(float_ns as u64)))
     }
 
     pub fn to_ns_factor(&self) -> f64 {
@@ -155,7 +806,7 @@ impl<'de> Deserialize<'de> for TimestampUnit {
 impl JsonConfig {
     pub fn load_merge_with_opts(
         rf_opts: ReflectorOpts,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let cfg = if let Some(cfg_path) = &rf_opts.config_file {
             modality_reflector_config::try_from_file(cfg_path)?
         } else if let Ok(env_path) = env::var(CONFIG_ENV_VAR) {
@@ -202,3 +853,28 @@ impl JsonConfig {
         AuthTokenBytes::resolve(self.auth_token.as_deref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn attr_coercions_require_a_path_and_type() {
+        let coercion: AttrCoercion =
+            serde_json::from_value(json!({"path": "code", "to": "hex-int"})).unwrap();
+        assert_eq!(
+            coercion,
+            AttrCoercion {
+                path: "code".to_string(),
+                to: CoercionType::HexInt,
+                on_error: CoercionErrorAction::Fail,
+                units: None,
+            }
+        );
+
+        assert!(serde_json::from_value::<AttrCoercion>(json!({"path": "code"})).is_err());
+        assert!(serde_json::from_value::<AttrCoercion>(json!({"to": "int"})).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use crate::error::Error;
+use async_trait::async_trait;
+use modality_api::{AttrVal, TimelineId};
+use modality_ingest_client::dynamic::DynamicIngestClient;
+use modality_ingest_client::IngestClient;
+use modality_ingest_protocol::InternedAttrKey;
+use std::time::Duration;
+use url::Url;
+
+/// The ingest protocol operations used by [`Client`](crate::client::Client)
+#[async_trait]
+pub trait IngestConnection: Send {
+    async fn declare_attr_key(&mut self, key: String) -> Result<InternedAttrKey, Error>;
+
+    async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error>;
+
+    async fn timeline_metadata(
+        &mut self,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error>;
+
+    async fn event(
+        &mut self,
+        ordering: u128,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error>;
+
+    async fn flush(&mut self) -> Result<(), Error>;
+}
+
+/// Establishes (authenticated) ingest connections, so that [`Client`](crate::client::Client)
+/// can reconnect when a connection fails
+#[async_trait]
+pub trait IngestConnector: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn IngestConnection>, Error>;
+}
+
+/// Connects to modalityd's ingest protocol endpoint
+#[derive(Clone, Debug)]
+pub struct TcpIngestConnector {
+    pub url: Url,
+    pub allow_insecure_tls: bool,
+    pub timeout: Duration,
+    pub auth_token: Vec<u8>,
+}
+
+#[async_trait]
+impl IngestConnector for TcpIngestConnector {
+    async fn connect(&self) -> Result<Box<dyn IngestConnection>, Error> {
+        let c =
+            IngestClient::connect_with_timeout(&self.url, self.allow_insecure_tls, self.timeout)
+                .await?;
+        let c_authed = c
+            .authenticate(self.auth_token.clone())
+            .await
+            .map_err(|e| Error::Authentication(Box::new(e.into())))?;
+        let c: DynamicIngestClient = c_authed.into();
+        Ok(Box::new(c))
+    }
+}
+
+/// How [`Client`](crate::client::Client) retries after the ingest connection fails
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of reconnect attempts before giving up
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given (zero-based) reconnect attempt, doubling each time
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+#[async_trait]
+impl IngestConnection for DynamicIngestClient {
+    async fn declare_attr_key(&mut self, key: String) -> Result<InternedAttrKey, Error> {
+        Ok(DynamicIngestClient::declare_attr_key(self, key).await?)
+    }
+
+    async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error> {
+        Ok(DynamicIngestClient::open_timeline(self, id).await?)
+    }
+
+    async fn timeline_metadata(
+        &mut self,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        Ok(DynamicIngestClient::timeline_metadata(self, attrs).await?)
+    }
+
+    async fn event(
+        &mut self,
+        ordering: u128,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        Ok(DynamicIngestClient::event(self, ordering, attrs).await?)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(DynamicIngestClient::flush(self).await?)
+    }
+}
@@ -1,3 +1,5 @@
+use crate::config::{CoercionError, TimestampError};
+use modality_api::AttrKey;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -11,6 +13,75 @@ pub enum Error {
     #[error("Encountered an ingest client error. {0}")]
     DynamicIngest(#[from] modality_ingest_client::dynamic::DynamicIngestError),
 
+    #[error("Failed to authenticate with modalityd. {0}")]
+    Authentication(#[source] Box<Error>),
+
+    #[error("Encountered an invalid attr key rename pattern. {0}")]
+    InvalidRenamePattern(#[from] regex::Error),
+
+    #[error("Encountered an invalid redaction pattern. {0}")]
+    InvalidRedactionPattern(regex::Error),
+
+    #[error("A redaction hash key is required for the 'hash' redaction action. Provide one in the configuration or via the MODALITY_JSON_REDACTION_KEY environment variable.")]
+    RedactionHashKeyRequired,
+
+    #[error("Found non-json data. Please supply the '--non-json-regex' option to parse it.")]
+    NoNonJsonRules,
+
+    #[error("Non-json line did not match any rule.")]
+    UnmatchedLine,
+
+    #[error("Regex capture group {0} has no name; name it with '(?P<name>...)' or specify attrs with --non-json-attr")]
+    UnnamedCaptureGroup(usize),
+
+    #[error("Regex capture for non-json attr '{0}' had no corresponding match.")]
+    MissingCapture(AttrKey),
+
+    #[error("Requested non-json attr '{0}' has no corresponding regex capture.")]
+    MissingCaptureGroup(AttrKey),
+
+    #[error("Regex capture '{0}' has no corresponding attr; specify with --non-json-attr")]
+    MissingCaptureAttr(String),
+
+    #[error("Expected JSON object at top level, or in array.")]
+    ExpectedObject,
+
+    #[error("Found null value for '{0}', and the null policy is 'fail'.")]
+    NullValue(AttrKey),
+
+    #[error("Failed to coerce attr '{key}'. {source}")]
+    Coercion { key: AttrKey, source: CoercionError },
+
+    #[error(
+        "Could not determine timeline name and identity for event. \
+         Make sure 'timeline-name' is given, and at least one choice is present for each input event."
+    )]
+    MissingTimelineName,
+
+    #[error(
+        "Could not determine event name. \
+         Make sure 'event-name' is given, and at least one choice is present for each input event."
+    )]
+    MissingEventName,
+
+    #[error("Invalid timestamp. {0}")]
+    Timestamp(#[from] TimestampError),
+
+    #[error("Failed to read or write the ingest recording. {0}")]
+    Recording(std::io::Error),
+
+    #[error("Invalid ingest recording, at line {line}. {source}")]
+    InvalidRecording {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("The ingest recording uses attr key {0} before declaring it.")]
+    UnknownRecordedAttrKey(u32),
+
+    #[error("Failed to write events to the sink. {0}")]
+    Sink(std::io::Error),
+
     #[error(transparent)]
     Auth(#[from] crate::auth::AuthTokenError),
 
@@ -19,3 +90,14 @@ pub enum Error {
     )]
     InvalidAttrKeyPrefix,
 }
+
+impl Error {
+    /// Errors which may be resolved by reconnecting to modalityd. Authentication failures
+    /// aren't: reconnecting with the same credentials would fail the same way.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            Error::IngestClientInitialization(_) | Error::Ingest(_) | Error::DynamicIngest(_)
+        )
+    }
+}
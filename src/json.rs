@@ -0,0 +1,199 @@
+//! A json value which borrows its object keys and strings from the input text where it can,
+//! so that records can be parsed and flattened without copying them.
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Like [`serde_json::Value`], but keys and strings are only copied when they
+/// contain escapes. Objects keep the semantics of [`serde_json::Map`]: their
+/// keys are sorted, and the last of any duplicate keys wins.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue<'a> {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(Cow<'a, str>),
+    Array(Vec<JsonValue<'a>>),
+    Object(Vec<(Cow<'a, str>, JsonValue<'a>)>),
+}
+
+impl<'a> JsonValue<'a> {
+    pub fn as_object(&self) -> Option<&[(Cow<'a, str>, JsonValue<'a>)]> {
+        match self {
+            JsonValue::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, JsonValue::Null)
+    }
+}
+
+/// Borrows from the value, without copying any strings
+impl<'a> From<&'a serde_json::Value> for JsonValue<'a> {
+    fn from(val: &'a serde_json::Value) -> Self {
+        match val {
+            serde_json::Value::Null => JsonValue::Null,
+            serde_json::Value::Bool(b) => JsonValue::Bool(*b),
+            serde_json::Value::Number(n) => JsonValue::Number(n.clone()),
+            serde_json::Value::String(s) => JsonValue::String(Cow::Borrowed(s)),
+            serde_json::Value::Array(a) => {
+                JsonValue::Array(a.iter().map(JsonValue::from).collect())
+            }
+            serde_json::Value::Object(o) => JsonValue::Object(
+                o.iter()
+                    .map(|(k, v)| (Cow::Borrowed(k.as_str()), JsonValue::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonValue<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(JsonValueVisitor)
+    }
+}
+
+struct JsonValueVisitor;
+
+impl<'de> Visitor<'de> for JsonValueVisitor {
+    type Value = JsonValue<'de>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any valid json value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(JsonValue::Null)
+    }
+
+    fn visit_bool<E>(self, b: bool) -> Result<Self::Value, E> {
+        Ok(JsonValue::Bool(b))
+    }
+
+    fn visit_i64<E>(self, i: i64) -> Result<Self::Value, E> {
+        Ok(JsonValue::Number(i.into()))
+    }
+
+    fn visit_u64<E>(self, u: u64) -> Result<Self::Value, E> {
+        Ok(JsonValue::Number(u.into()))
+    }
+
+    fn visit_f64<E>(self, f: f64) -> Result<Self::Value, E> {
+        Ok(serde_json::Number::from_f64(f).map_or(JsonValue::Null, JsonValue::Number))
+    }
+
+    fn visit_borrowed_str<E>(self, s: &'de str) -> Result<Self::Value, E> {
+        Ok(JsonValue::String(Cow::Borrowed(s)))
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E> {
+        Ok(JsonValue::String(Cow::Owned(s.to_string())))
+    }
+
+    fn visit_string<E>(self, s: String) -> Result<Self::Value, E> {
+        Ok(JsonValue::String(Cow::Owned(s)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut vals = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(val) = seq.next_element()? {
+            vals.push(val);
+        }
+        Ok(JsonValue::Array(vals))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries: Vec<(Cow<'de, str>, JsonValue<'de>)> =
+            Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(key) = map.next_key::<JsonValue<'de>>()? {
+            let JsonValue::String(key) = key else {
+                return Err(de::Error::custom("expected a string object key"));
+            };
+            entries.push((key, map.next_value()?));
+        }
+
+        // The sort is stable, so duplicate keys stay in document order
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut obj: Vec<(Cow<'de, str>, JsonValue<'de>)> = Vec::with_capacity(entries.len());
+        for (k, v) in entries {
+            match obj.last_mut() {
+                Some(last) if last.0 == k => last.1 = v,
+                _ => obj.push((k, v)),
+            }
+        }
+        Ok(JsonValue::Object(obj))
+    }
+}
+
+impl<'a> Serialize for JsonValue<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            JsonValue::Null => serializer.serialize_unit(),
+            JsonValue::Bool(b) => serializer.serialize_bool(*b),
+            JsonValue::Number(n) => n.serialize(serializer),
+            JsonValue::String(s) => serializer.serialize_str(s),
+            JsonValue::Array(vals) => {
+                let mut seq = serializer.serialize_seq(Some(vals.len()))?;
+                for val in vals {
+                    seq.serialize_element(val)?;
+                }
+                seq.end()
+            }
+            JsonValue::Object(obj) => {
+                let mut map = serializer.serialize_map(Some(obj.len()))?;
+                for (k, v) in obj {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Renders the value as compact json
+impl<'a> fmt::Display for JsonValue<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn borrows_unescaped_strings() {
+        let input = r#"{"plain": "value", "esc\"aped": "a\nb"}"#;
+        let val: JsonValue = serde_json::from_str(input).unwrap();
+        let obj = val.as_object().unwrap();
+
+        assert_eq!(obj[0].0, "esc\"aped");
+        assert!(matches!(obj[0].0, Cow::Owned(_)));
+        assert!(matches!(obj[0].1, JsonValue::String(Cow::Owned(_))));
+        assert_eq!(obj[1].0, "plain");
+        assert!(matches!(obj[1].0, Cow::Borrowed(_)));
+        assert!(matches!(obj[1].1, JsonValue::String(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn matches_serde_json_value() {
+        let input = r#"{"b": [1, -2, 3.5, true, null], "a": {"z": "x", "y": {}}, "b": 7}"#;
+        let val: JsonValue = serde_json::from_str(input).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(input).unwrap();
+
+        assert_eq!(val, JsonValue::from(&expected));
+        assert_eq!(val.to_string(), expected.to_string());
+        assert_eq!(
+            val.to_string(),
+            json!({"a": {"y": {}, "z": "x"}, "b": 7}).to_string()
+        );
+    }
+}
@@ -1,8 +1,17 @@
 pub mod auth;
+pub mod checkpoint;
 pub mod client;
 pub mod config;
+pub mod connection;
 pub mod error;
+pub mod json;
+pub mod mapper;
 pub mod opts;
 pub mod prelude;
+pub mod record;
+pub mod redact;
+pub mod rename;
+pub mod report;
+pub mod sink;
 pub mod tracing;
 pub mod types;
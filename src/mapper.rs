@@ -0,0 +1,1385 @@
+//! The mapping engine: turns json values, and lines of text, into modality events
+//! according to a [`PluginConfig`].
+
+use crate::config::{
+    AttrCoercion, CoercionErrorAction, KeySegmentPolicy, LineDisposition, NullPolicy, PluginConfig,
+    UnmatchedLineAction,
+};
+use crate::error::Error;
+use crate::json::JsonValue;
+use crate::redact::Redactor;
+use fxhash::{FxHashMap, FxHashSet, FxHasher};
+use itertools::Itertools;
+use modality_api::{AttrKey, AttrVal, BigInt, TimelineId};
+use regex::{Regex, RegexSet};
+use std::borrow::Cow;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+/// An event, ready to be sent to an [`EventSink`](crate::sink::EventSink)
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappedEvent {
+    pub timeline_id: TimelineId,
+    pub timeline_kvs: Vec<(AttrKey, AttrVal)>,
+    pub event_kvs: Vec<(AttrKey, AttrVal)>,
+}
+
+/// The timeline name attr which identifies a timeline
+pub type TimelineNameSig = (AttrKey, AttrVal);
+
+/// Timeline ids by timeline name. Clones share the same ids, so that several
+/// mappers (e.g. on different threads) give each timeline the same id.
+#[derive(Clone, Debug, Default)]
+pub struct TimelineRegistry(Arc<Mutex<FxHashMap<TimelineNameSig, TimelineId>>>);
+
+impl TimelineRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The timeline's id, allocating one if this is the first time it's been seen
+    pub fn get_or_allocate(&self, timeline_name_sig: TimelineNameSig) -> TimelineId {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(timeline_name_sig)
+            .or_insert_with(TimelineId::allocate)
+    }
+
+    pub fn extend(&self, timelines: impl IntoIterator<Item = (TimelineNameSig, TimelineId)>) {
+        self.0.lock().unwrap().extend(timelines);
+    }
+
+    /// The timeline ids allocated (or given) so far
+    pub fn to_vec(&self) -> Vec<(TimelineNameSig, TimelineId)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(sig, id)| (sig.clone(), *id))
+            .collect()
+    }
+}
+
+/// Maps json objects (and attrs extracted from non-json lines) to events, according to a
+/// [`PluginConfig`]: json key paths are flattened into attr keys, attrs are coerced and
+/// redacted, and split into timeline and event attrs, and the timeline and event names are
+/// determined. Each distinct timeline name gets a timeline id the first time it's seen.
+///
+/// Clones share the same [`TimelineRegistry`].
+#[derive(Clone)]
+pub struct JsonEventMapper {
+    cfg: PluginConfig,
+    key_formatter: KeyPathFormatter,
+    redactor: Redactor,
+    timelines: TimelineRegistry,
+}
+
+impl JsonEventMapper {
+    pub fn new(cfg: &PluginConfig) -> Result<Self, Error> {
+        Ok(Self {
+            cfg: cfg.clone(),
+            key_formatter: KeyPathFormatter::new(cfg),
+            redactor: Redactor::new(&cfg.redact, cfg.redaction_hash_key.as_deref())?,
+            timelines: Default::default(),
+        })
+    }
+
+    /// Use the given timeline ids for these timeline names, e.g. when resuming an import
+    pub fn with_known_timelines(
+        mut self,
+        timelines: impl IntoIterator<Item = (TimelineNameSig, TimelineId)>,
+    ) -> Self {
+        self.timelines.extend(timelines);
+        self
+    }
+
+    /// Share timeline ids with other mappers which use the same registry
+    pub fn with_timeline_registry(mut self, timelines: TimelineRegistry) -> Self {
+        self.timelines = timelines;
+        self
+    }
+
+    pub fn timeline_registry(&self) -> &TimelineRegistry {
+        &self.timelines
+    }
+
+    /// The timeline ids allocated (or given) so far
+    pub fn known_timelines(&self) -> Vec<(TimelineNameSig, TimelineId)> {
+        self.timelines.to_vec()
+    }
+
+    /// Map a json object to an event. 'extra_kvs' are added to the attrs from the object.
+    pub fn map_json(
+        &mut self,
+        val: &serde_json::Value,
+        extra_kvs: &[(AttrKey, AttrVal)],
+    ) -> Result<MappedEvent, Error> {
+        self.map_json_value(&JsonValue::from(val), extra_kvs)
+    }
+
+    /// Map a json object, parsed as a [`JsonValue`] which borrows from the input, to an event.
+    /// 'extra_kvs' are added to the attrs from the object. Coercions are applied to the
+    /// object's attrs, but not to 'extra_kvs', which are expected to have been coerced when
+    /// they were extracted.
+    pub fn map_json_value(
+        &mut self,
+        val: &JsonValue,
+        extra_kvs: &[(AttrKey, AttrVal)],
+    ) -> Result<MappedEvent, Error> {
+        let Some(obj) = val.as_object() else {
+            return Err(Error::ExpectedObject);
+        };
+
+        let cfg = &self.cfg;
+        let null_policy = cfg.null_policy.unwrap_or_default();
+        let mut null_key = None;
+
+        let mut all_kvs = extra_kvs.to_vec();
+        walk_obj(obj, |key_path, val| {
+            if val.is_null() {
+                match null_policy {
+                    NullPolicy::Drop => (),
+                    NullPolicy::Sentinel => {
+                        let sentinel = cfg.null_sentinel.as_deref().unwrap_or("null");
+                        all_kvs.push((
+                            self.key_formatter.format(key_path),
+                            AttrVal::String(sentinel.to_string().into()),
+                        ));
+                    }
+                    NullPolicy::IsNull => {
+                        key_path.push(Cow::Borrowed("is_null"));
+                        all_kvs.push((self.key_formatter.format(key_path), AttrVal::Bool(true)));
+                        key_path.pop();
+                    }
+                    NullPolicy::Fail => {
+                        if null_key.is_none() {
+                            null_key = Some(self.key_formatter.format(key_path));
+                        }
+                    }
+                }
+            } else if let Some(val) = json_leaf_to_attr_val(val) {
+                all_kvs.push((self.key_formatter.format(key_path), val));
+            }
+        });
+
+        if let Some(key) = null_key {
+            return Err(Error::NullValue(key));
+        }
+
+        if !cfg.coerce_attrs.is_empty() {
+            coerce_attrs(&mut all_kvs[extra_kvs.len()..], &cfg.coerce_attrs)?;
+        }
+
+        prepare_event(all_kvs, cfg, &self.redactor, &self.timelines)
+    }
+
+    /// Map already extracted (and coerced) attrs, e.g. from a non-json line, to an event
+    pub fn map_attrs(&mut self, kvs: Vec<(AttrKey, AttrVal)>) -> Result<MappedEvent, Error> {
+        prepare_event(kvs, &self.cfg, &self.redactor, &self.timelines)
+    }
+
+    /// Map already extracted attrs to an event, using a different config for the
+    /// timeline and event names than the mapper's own, such as a non-json rule's
+    pub fn map_attrs_with_config(
+        &mut self,
+        kvs: Vec<(AttrKey, AttrVal)>,
+        cfg: &PluginConfig,
+    ) -> Result<MappedEvent, Error> {
+        prepare_event(kvs, cfg, &self.redactor, &self.timelines)
+    }
+}
+
+/// Parses lines that don't look like json, using the non-json-regex and
+/// non-json-rules from the config. A RegexSet is used to find the first
+/// matching rule, so only that rule's regex needs to be run for captures.
+pub struct NonJsonLineParser {
+    set: RegexSet,
+    rules: Vec<NonJsonLineRule>,
+    unmatched_line_action: UnmatchedLineAction,
+    multiline: Option<MultiLine>,
+    coercions: Vec<AttrCoercion>,
+}
+
+/// How non-json lines are grouped into multi-line records
+struct MultiLine {
+    start_re: Option<Regex>,
+    indent_continuation: bool,
+    body_attr: Option<AttrKey>,
+    max_lines: usize,
+}
+
+impl MultiLine {
+    fn new(cfg: &PluginConfig) -> Result<Option<Self>, regex::Error> {
+        if cfg.multiline_start_regex.is_none() && !cfg.multiline_indent_continuation {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            start_re: cfg
+                .multiline_start_regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()?,
+            indent_continuation: cfg.multiline_indent_continuation,
+            body_attr: cfg.multiline_body_attr.clone().map(AttrKey::new),
+            max_lines: cfg.multiline_max_lines.unwrap_or(1000),
+        }))
+    }
+
+    fn is_continuation(&self, line: &str) -> bool {
+        // A json value is never part of a multi-line record, even when it's indented
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with(['{', '[']) {
+            return false;
+        }
+
+        if self.indent_continuation && line.starts_with([' ', '\t']) {
+            return true;
+        }
+
+        match &self.start_re {
+            Some(re) => !re.is_match(line),
+            None => false,
+        }
+    }
+
+    /// Split the next record off the front of 's'. Returns the record and the remaining input.
+    fn take_record<'a>(&self, s: &'a str) -> (&'a str, &'a str) {
+        let mut end = s.find('\n').unwrap_or(s.len());
+        let mut num_lines = 1;
+        while end < s.len() && num_lines < self.max_lines {
+            let rest = &s[end + 1..];
+            let next_len = rest.find('\n').unwrap_or(rest.len());
+            if !self.is_continuation(rest[..next_len].trim_end_matches('\r')) {
+                break;
+            }
+            end += 1 + next_len;
+            num_lines += 1;
+        }
+
+        (s[..end].trim_end_matches('\r'), &s[end..])
+    }
+}
+
+struct NonJsonLineRule {
+    re: Regex,
+    attrs: Vec<AttrKey>,
+    disposition: LineDisposition,
+    /// The mapping config for the 'event' disposition
+    event_cfg: Option<PluginConfig>,
+}
+
+pub enum NonJsonLine<'p> {
+    /// Attrs to attach to the next json object
+    Attrs(Vec<(AttrKey, AttrVal)>),
+    /// Attrs to send as an event of their own, using the given mapping config
+    Event(Vec<(AttrKey, AttrVal)>, &'p PluginConfig),
+    /// Nothing to do for this line
+    Skip,
+}
+
+impl NonJsonLineParser {
+    pub fn new(cfg: &PluginConfig) -> Result<Self, regex::Error> {
+        let to_attr_keys = |attrs: &[String]| -> Vec<AttrKey> {
+            attrs.iter().map(|k| AttrKey::new(k.clone())).collect()
+        };
+
+        let mut rules = vec![];
+        if let Some(re) = &cfg.non_json_regex {
+            rules.push(NonJsonLineRule {
+                re: Regex::new(re)?,
+                attrs: to_attr_keys(&cfg.non_json_attrs),
+                disposition: LineDisposition::Attach,
+                event_cfg: None,
+            });
+        }
+        for rule in cfg.non_json_rules.iter() {
+            rules.push(NonJsonLineRule {
+                re: Regex::new(&rule.regex)?,
+                attrs: to_attr_keys(&rule.attrs),
+                disposition: rule.disposition,
+                event_cfg: (rule.disposition == LineDisposition::Event)
+                    .then(|| rule.event_config(cfg)),
+            });
+        }
+
+        Ok(Self {
+            set: RegexSet::new(rules.iter().map(|r| r.re.as_str()))?,
+            rules,
+            unmatched_line_action: cfg.unmatched_line_action.unwrap_or_default(),
+            multiline: MultiLine::new(cfg)?,
+            coercions: cfg.coerce_attrs.clone(),
+        })
+    }
+
+    /// Split the next line (or multi-line record) off the front of 's'. Returns the line and
+    /// the remaining input.
+    pub fn take_line<'a>(&self, s: &'a str) -> (&'a str, &'a str) {
+        match &self.multiline {
+            Some(multiline) => multiline.take_record(s),
+            None => {
+                let line = s.lines().next().unwrap_or_default();
+                (line, &s[line.len()..])
+            }
+        }
+    }
+
+    pub fn parse_line(&self, line: &str) -> Result<NonJsonLine<'_>, Error> {
+        // Without any rules, every line is unmatched
+        let Some(rule) = self.set.matches(line).iter().next().map(|i| &self.rules[i]) else {
+            return match self.unmatched_line_action {
+                UnmatchedLineAction::Skip => Ok(NonJsonLine::Skip),
+                UnmatchedLineAction::Warn => {
+                    warn!("Non-json line did not match any rule: {line}");
+                    Ok(NonJsonLine::Skip)
+                }
+                UnmatchedLineAction::Fail if self.rules.is_empty() => Err(Error::NoNonJsonRules),
+                UnmatchedLineAction::Fail => Err(Error::UnmatchedLine),
+            };
+        };
+
+        if rule.disposition == LineDisposition::Discard {
+            return Ok(NonJsonLine::Skip);
+        }
+
+        let caps = rule.re.captures(line).ok_or(Error::UnmatchedLine)?;
+        let mut out_attrs = capture_attrs(&rule.re, &caps, &rule.attrs, &self.coercions)?;
+
+        if let Some(body_attr) = self.multiline.as_ref().and_then(|m| m.body_attr.as_ref()) {
+            if let Some((_first_line, body)) = line.split_once('\n') {
+                let mut body = AttrVal::String(body.to_string().into());
+                coerce_attr(body_attr, &mut body, &self.coercions)?;
+                out_attrs.push((body_attr.clone(), body));
+            }
+        }
+
+        match &rule.event_cfg {
+            Some(event_cfg) => Ok(NonJsonLine::Event(out_attrs, event_cfg)),
+            None => Ok(NonJsonLine::Attrs(out_attrs)),
+        }
+    }
+}
+
+/// Parses lines made of a text prefix followed by a json object, using json-prefix-regex
+pub struct JsonPrefix {
+    re: Regex,
+    attrs: Vec<AttrKey>,
+    coercions: Vec<AttrCoercion>,
+}
+
+impl JsonPrefix {
+    pub fn new(cfg: &PluginConfig) -> Result<Option<Self>, regex::Error> {
+        let Some(re) = &cfg.json_prefix_regex else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            re: Regex::new(re)?,
+            attrs: cfg
+                .json_prefix_attrs
+                .iter()
+                .map(|k| AttrKey::new(k.clone()))
+                .collect(),
+            coercions: cfg.coerce_attrs.clone(),
+        }))
+    }
+
+    /// If the line at the front of 's' starts with text matching the prefix regex, followed by
+    /// a json object, returns the attrs extracted from the prefix and the remaining input,
+    /// starting at the json object.
+    #[allow(clippy::type_complexity)]
+    pub fn split<'a>(
+        &self,
+        s: &'a str,
+    ) -> Result<Option<(Vec<(AttrKey, AttrVal)>, &'a str)>, Error> {
+        let line = s.lines().next().unwrap_or_default();
+        let Some(caps) = self.re.captures(line) else {
+            return Ok(None);
+        };
+
+        // the first capture is always the entire match
+        let prefix_end = caps.get(0).map(|m| m.end()).unwrap_or_default();
+        if caps.get(0).map(|m| m.start()) != Some(0) {
+            return Ok(None);
+        }
+
+        let rest = &line[prefix_end..];
+        let json_start = prefix_end + (rest.len() - rest.trim_start().len());
+        if !line[json_start..].starts_with('{') {
+            return Ok(None);
+        }
+
+        let kvs = capture_attrs(&self.re, &caps, &self.attrs, &self.coercions)?;
+        Ok(Some((kvs, &s[json_start..])))
+    }
+}
+
+/// Turn regex captures into attrs. If attr keys are given, they're assigned to the capture
+/// groups positionally. Otherwise the name of each capture group is used as its attr key, and
+/// groups which didn't participate in the match are skipped. Coercions are applied to the
+/// captured text.
+fn capture_attrs(
+    re: &Regex,
+    caps: &regex::Captures,
+    attrs: &[AttrKey],
+    coercions: &[AttrCoercion],
+) -> Result<Vec<(AttrKey, AttrVal)>, Error> {
+    let mut out_attrs = vec![];
+
+    if attrs.is_empty() {
+        // the first capture is always the entire match, and is never named
+        for (i, name) in re.capture_names().enumerate().skip(1) {
+            let Some(name) = name else {
+                return Err(Error::UnnamedCaptureGroup(i));
+            };
+            if let Some(capture) = caps.get(i) {
+                let key = AttrKey::new(name.to_string());
+                let val = capture_attr_val(&key, capture.as_str(), coercions)?;
+                out_attrs.push((key, val));
+            }
+        }
+
+        return Ok(out_attrs);
+    }
+
+    // the first capture is always the entire match
+    let caps = caps.iter().skip(1);
+
+    for eob in attrs.iter().zip_longest(caps) {
+        match eob {
+            itertools::EitherOrBoth::Both(attr, capture) => {
+                let capture = capture.ok_or_else(|| Error::MissingCapture(attr.clone()))?;
+                let val = capture_attr_val(attr, capture.as_str(), coercions)?;
+                out_attrs.push((attr.clone(), val));
+            }
+            itertools::EitherOrBoth::Left(attr) => {
+                return Err(Error::MissingCaptureGroup(attr.clone()));
+            }
+            itertools::EitherOrBoth::Right(capture) => {
+                return Err(Error::MissingCaptureAttr(
+                    capture
+                        .map(|c| c.as_str())
+                        .unwrap_or("<no match>")
+                        .to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(out_attrs)
+}
+
+/// Heuristically try to get a reasonably-typed attr val from this
+pub fn string_to_attr_val(s: &str) -> AttrVal {
+    if s.contains('.') {
+        if let Ok(f) = s.parse::<f64>() {
+            return AttrVal::Float(f.into());
+        }
+    }
+
+    if let Ok(i) = s.parse::<i128>() {
+        return BigInt::new_attr_val(i);
+    }
+
+    AttrVal::String(s.to_string().into())
+}
+
+/// Split the given attrs into timeline and event attrs, and determine the timeline and event
+/// names, according to the given config
+fn prepare_event(
+    mut all_kvs: Vec<(AttrKey, AttrVal)>,
+    cfg: &PluginConfig,
+    redactor: &Redactor,
+    timelines: &TimelineRegistry,
+) -> Result<MappedEvent, Error> {
+    if !redactor.is_empty() {
+        redactor.redact(&mut all_kvs);
+    }
+
+    let mut timeline_kvs = vec![];
+    let mut event_kvs = vec![];
+    for (key, val) in all_kvs.into_iter() {
+        if cfg.timeline_names.iter().any(|s| s == key.as_ref())
+            || cfg.timeline_attrs.iter().any(|s| s == key.as_ref())
+        {
+            timeline_kvs.push((key, val));
+        } else {
+            event_kvs.push((key, val));
+        }
+    }
+
+    let mut timeline_name_sig = None;
+    let mut timeline_name = cfg.timeline_name_prefix.clone().unwrap_or_default();
+
+    for name_key in cfg.timeline_names.iter() {
+        if let Some((k, v)) = timeline_kvs.iter().find(|(k, _)| k.as_ref() == name_key) {
+            timeline_name_sig = Some((k.clone(), v.clone()));
+            timeline_name += &v.to_string();
+            break;
+        }
+    }
+
+    let timeline_name_sig = timeline_name_sig.ok_or(Error::MissingTimelineName)?;
+
+    if !timeline_name.is_empty() {
+        timeline_kvs.push((AttrKey::new("name".into()), timeline_name.into()));
+    }
+
+    let timeline_id = timelines.get_or_allocate(timeline_name_sig);
+
+    let mut event_name = cfg.event_name_prefix.clone().unwrap_or_default();
+    for name_key in cfg.event_names.iter() {
+        if let Some((_k, v)) = event_kvs.iter().find(|(k, _)| k.as_ref() == name_key) {
+            event_name += &v.to_string();
+            break;
+        }
+    }
+
+    if event_name.is_empty() {
+        return Err(Error::MissingEventName);
+    }
+    event_kvs.push((AttrKey::new("name".into()), event_name.into()));
+
+    if let Some(ta) = &cfg.timestamp_attr {
+        if let Some((_, val)) = event_kvs.iter().find(|(k, _)| ta == k.as_ref()) {
+            let units = cfg.timestamp_attr_units.unwrap_or_default();
+            let val = units.attr_val_to_ns(val)?;
+            event_kvs.push((AttrKey::new("timestamp".into()), val.clone()));
+        }
+    }
+
+    Ok(MappedEvent {
+        timeline_id,
+        timeline_kvs,
+        event_kvs,
+    })
+}
+
+fn coerce_attrs(kvs: &mut [(AttrKey, AttrVal)], coercions: &[AttrCoercion]) -> Result<(), Error> {
+    for (key, val) in kvs.iter_mut() {
+        coerce_attr(key, val, coercions)?;
+    }
+
+    Ok(())
+}
+
+/// Coerce the value of an attr, if there's a coercion for its key. Returns whether the value
+/// was coerced.
+fn coerce_attr(
+    key: &AttrKey,
+    val: &mut AttrVal,
+    coercions: &[AttrCoercion],
+) -> Result<bool, Error> {
+    let Some(coercion) = coercions.iter().find(|c| c.path == key.as_ref()) else {
+        return Ok(false);
+    };
+
+    match coercion.to.coerce(val, coercion.units.unwrap_or_default()) {
+        Ok(coerced) => {
+            *val = coerced;
+            Ok(true)
+        }
+        Err(e) => match coercion.on_error {
+            CoercionErrorAction::Fail => Err(Error::Coercion {
+                key: key.clone(),
+                source: e,
+            }),
+            CoercionErrorAction::Fallback => {
+                debug!("Failed to coerce attr '{key}', using the original value. {e}");
+                Ok(false)
+            }
+        },
+    }
+}
+
+/// The attr val for a regex capture: the capture's text, coerced if there's a coercion for
+/// its key, and otherwise typed by [`string_to_attr_val`]
+fn capture_attr_val(
+    key: &AttrKey,
+    capture: &str,
+    coercions: &[AttrCoercion],
+) -> Result<AttrVal, Error> {
+    let mut val = AttrVal::String(capture.to_string().into());
+    if coerce_attr(key, &mut val, coercions)? {
+        Ok(val)
+    } else {
+        Ok(string_to_attr_val(capture))
+    }
+}
+
+pub fn json_leaf_to_attr_val(val: &JsonValue) -> Option<AttrVal> {
+    match val {
+        // We never call this function with an array or object
+        JsonValue::Array(_) => None,
+        JsonValue::Object(_) => None,
+        JsonValue::Null => None,
+        JsonValue::Bool(b) => Some(AttrVal::Bool(*b)),
+        JsonValue::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Some(AttrVal::Integer(i))
+            } else if let Some(u) = n.as_u64() {
+                Some(BigInt::new_attr_val(u as i128))
+            } else if let Some(f) = n.as_f64() {
+                Some(AttrVal::Float(f.into()))
+            } else {
+                // There are just three variants of `Number` in serde-json, and they're handled above.
+                unreachable!()
+            }
+        }
+        JsonValue::String(s) => Some(AttrVal::String(s.as_ref().to_string().into())),
+    }
+}
+
+pub type JsonPath<'a> = Vec<Cow<'a, str>>;
+
+/// How many distinct attr keys [`KeyPathFormatter`] remembers the source path of, for
+/// collision warnings. Keys beyond this aren't checked.
+const MAX_COLLISION_CHECKED_KEYS: usize = 100_000;
+
+/// Turns flattened json key paths into attr keys, using the configured
+/// separator and key segment policy. Warns (once per key) when two different
+/// source paths produce the same attr key.
+#[derive(Clone)]
+pub struct KeyPathFormatter {
+    separator: String,
+    policy: KeySegmentPolicy,
+    replacement: String,
+    /// A hash of the first source path of each attr key
+    sources: FxHashMap<String, u64>,
+    collisions: FxHashSet<String>,
+    /// Reused for each key, so it only has to grow once
+    key: String,
+}
+
+impl KeyPathFormatter {
+    pub fn new(cfg: &PluginConfig) -> Self {
+        let separator = cfg
+            .key_path_separator
+            .clone()
+            .unwrap_or_else(|| ".".to_string());
+        // The replacement for the separator mustn't be the separator
+        let default_replacement = if separator.contains('_') { "-" } else { "_" };
+        Self {
+            policy: cfg.key_segment_policy.unwrap_or_default(),
+            replacement: cfg
+                .key_segment_replacement
+                .clone()
+                .unwrap_or_else(|| default_replacement.to_string()),
+            separator,
+            sources: Default::default(),
+            collisions: Default::default(),
+            key: String::new(),
+        }
+    }
+
+    pub fn format(&mut self, key_path: &[Cow<str>]) -> AttrKey {
+        let mut key = std::mem::take(&mut self.key);
+        key.clear();
+        for (i, segment) in key_path.iter().enumerate() {
+            if i > 0 {
+                key.push_str(&self.separator);
+            }
+            self.push_segment(&mut key, segment);
+        }
+
+        self.check_collision(&key, key_path);
+        let attr_key = AttrKey::new(key.clone());
+        self.key = key;
+        attr_key
+    }
+
+    fn push_segment(&self, key: &mut String, segment: &str) {
+        match self.policy {
+            KeySegmentPolicy::Keep => key.push_str(segment),
+            KeySegmentPolicy::Replace | KeySegmentPolicy::Strip => {
+                for c in segment.chars() {
+                    if KeySegmentPolicy::is_valid_char(c, &self.separator) {
+                        key.push(c);
+                    } else if self.policy == KeySegmentPolicy::Replace {
+                        key.push_str(&self.replacement);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_collision(&mut self, key: &str, key_path: &[Cow<str>]) {
+        let mut hasher = FxHasher::default();
+        for segment in key_path.iter() {
+            segment.as_ref().hash(&mut hasher);
+        }
+        let path_hash = hasher.finish();
+
+        match self.sources.get(key) {
+            None => {
+                if self.sources.len() < MAX_COLLISION_CHECKED_KEYS {
+                    self.sources.insert(key.to_string(), path_hash);
+                }
+            }
+            Some(source_hash) => {
+                if *source_hash != path_hash && self.collisions.insert(key.to_string()) {
+                    warn!(
+                        "Json path {} maps to the attr key '{key}', which another json path also maps to",
+                        json_pointer(key_path.iter().map(|s| s.as_ref())),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Render a key path as an (unambiguous) RFC 6901 json pointer, for diagnostics
+fn json_pointer<'a>(segments: impl Iterator<Item = &'a str>) -> String {
+    let mut out = String::new();
+    for segment in segments {
+        out.push('/');
+        out.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+    out
+}
+
+/// Do a depth-first traversal of a json object. Call 'f' at every leaf value (non-object,
+/// non-array), with the key path to it. The same path buffer is used for the whole traversal;
+/// 'f' may extend it, but must leave it as it was.
+pub fn walk_obj<'a: 'b, 'b>(
+    obj: &'b [(Cow<'a, str>, JsonValue<'a>)],
+    mut f: impl FnMut(&mut JsonPath<'b>, &'b JsonValue<'a>),
+) {
+    fn walk_rec<'a: 'b, 'b>(
+        path: &mut JsonPath<'b>,
+        val: &'b JsonValue<'a>,
+        f: &mut impl FnMut(&mut JsonPath<'b>, &'b JsonValue<'a>),
+    ) {
+        match val {
+            JsonValue::Object(obj) => {
+                for (k, v) in obj.iter() {
+                    path.push(Cow::Borrowed(k.as_ref()));
+                    walk_rec(path, v, f);
+                    path.pop();
+                }
+            }
+            JsonValue::Array(vals) => {
+                for (i, v) in vals.iter().enumerate() {
+                    path.push(Cow::Owned(i.to_string()));
+                    walk_rec(path, v, f);
+                    path.pop();
+                }
+            }
+            _ => f(path, val),
+        }
+    }
+
+    let mut path = vec![];
+    for (k, v) in obj.iter() {
+        path.push(Cow::Borrowed(k.as_ref()));
+        walk_rec(&mut path, v, &mut f);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CoercionType, TimestampUnit};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn cfg() -> PluginConfig {
+        PluginConfig {
+            timeline_names: vec!["component".to_string()],
+            timeline_attrs: vec!["component".to_string()],
+            event_names: vec!["msg".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn s(v: &str) -> AttrVal {
+        AttrVal::String(v.to_string().into())
+    }
+
+    fn sorted(kvs: &[(AttrKey, AttrVal)]) -> Vec<(String, AttrVal)> {
+        let mut kvs: Vec<_> = kvs
+            .iter()
+            .map(|(k, v)| (k.as_ref().to_string(), v.clone()))
+            .collect();
+        kvs.sort_by(|a, b| a.0.cmp(&b.0));
+        kvs
+    }
+
+    #[test]
+    fn maps_nested_objects_and_arrays() {
+        let mut mapper = JsonEventMapper::new(&cfg()).unwrap();
+        let ev = mapper
+            .map_json(
+                &json!({
+                    "component": "sensor",
+                    "msg": "measure",
+                    "data": {"temp": 55.2, "tags": ["a", "b"], "ok": true}
+                }),
+                &[],
+            )
+            .unwrap();
+
+        assert_eq!(
+            sorted(&ev.timeline_kvs),
+            vec![
+                ("component".to_string(), s("sensor")),
+                ("name".to_string(), s("sensor")),
+            ]
+        );
+        assert_eq!(
+            sorted(&ev.event_kvs),
+            vec![
+                ("data.ok".to_string(), AttrVal::Bool(true)),
+                ("data.tags.0".to_string(), s("a")),
+                ("data.tags.1".to_string(), s("b")),
+                ("data.temp".to_string(), AttrVal::Float(55.2.into())),
+                ("msg".to_string(), s("measure")),
+                ("name".to_string(), s("measure")),
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_extra_kvs() {
+        let mut mapper = JsonEventMapper::new(&cfg()).unwrap();
+        let extra = vec![(AttrKey::new("level".to_string()), s("INFO"))];
+        let ev = mapper
+            .map_json(&json!({"component": "c", "msg": "m"}), &extra)
+            .unwrap();
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("level".to_string()), s("INFO"))));
+    }
+
+    #[test]
+    fn allocates_one_timeline_id_per_timeline_name() {
+        let mut mapper = JsonEventMapper::new(&cfg()).unwrap();
+        let mut map = |component: &str| {
+            mapper
+                .map_json(&json!({"component": component, "msg": "m"}), &[])
+                .unwrap()
+                .timeline_id
+        };
+
+        let a = map("a");
+        let b = map("b");
+        assert_ne!(a, b);
+        assert_eq!(map("a"), a);
+    }
+
+    #[test]
+    fn uses_known_timelines() {
+        let id = TimelineId::allocate();
+        let mut mapper = JsonEventMapper::new(&cfg())
+            .unwrap()
+            .with_known_timelines([((AttrKey::new("component".to_string()), s("a")), id)]);
+
+        let ev = mapper
+            .map_json(&json!({"component": "a", "msg": "m"}), &[])
+            .unwrap();
+        assert_eq!(ev.timeline_id, id);
+        assert_eq!(mapper.known_timelines().len(), 1);
+    }
+
+    #[test]
+    fn maps_borrowed_json_like_serde_json_value() {
+        let input =
+            r#"{"component": "c", "msg": "m\u0021", "a": {"b": [1, {"c": null}], "d": "x"}}"#;
+        let cfg = PluginConfig {
+            null_policy: Some(NullPolicy::IsNull),
+            ..cfg()
+        };
+
+        let val: serde_json::Value = serde_json::from_str(input).unwrap();
+        let expected = JsonEventMapper::new(&cfg)
+            .unwrap()
+            .map_json(&val, &[])
+            .unwrap();
+
+        let borrowed: JsonValue = serde_json::from_str(input).unwrap();
+        let ev = JsonEventMapper::new(&cfg)
+            .unwrap()
+            .map_json_value(&borrowed, &[])
+            .unwrap();
+
+        assert_eq!(sorted(&ev.event_kvs), sorted(&expected.event_kvs));
+        assert!(ev.event_kvs.contains(&(
+            AttrKey::new("a.b.1.c.is_null".to_string()),
+            AttrVal::Bool(true)
+        )));
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("name".to_string()), s("m!"))));
+    }
+
+    #[test]
+    fn formats_key_paths() {
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            key_path_separator: Some("/".to_string()),
+            key_segment_policy: Some(KeySegmentPolicy::Replace),
+            ..cfg()
+        })
+        .unwrap();
+
+        let ev = mapper
+            .map_json(
+                &json!({"component": "c", "msg": "m", "http req": {"status.code": 200}}),
+                &[],
+            )
+            .unwrap();
+        assert!(ev.event_kvs.contains(&(
+            AttrKey::new("http_req/status_code".to_string()),
+            AttrVal::Integer(200)
+        )));
+    }
+
+    #[test]
+    fn treats_the_separator_as_invalid_in_segments() {
+        let cfg = |policy| PluginConfig {
+            key_path_separator: Some("_".to_string()),
+            key_segment_policy: Some(policy),
+            ..cfg()
+        };
+        let path = |segments: &[&'static str]| -> Vec<Cow<str>> {
+            segments.iter().map(|s| Cow::Borrowed(*s)).collect()
+        };
+
+        let mut formatter = KeyPathFormatter::new(&cfg(KeySegmentPolicy::Replace));
+        assert_eq!(formatter.format(&path(&["a_b", "c"])).as_ref(), "a-b_c");
+        assert_eq!(formatter.format(&path(&["a", "b_c"])).as_ref(), "a_b-c");
+        assert_eq!(formatter.format(&path(&["x-y", "z"])).as_ref(), "x-y_z");
+        assert!(formatter.collisions.is_empty());
+
+        let mut formatter = KeyPathFormatter::new(&cfg(KeySegmentPolicy::Strip));
+        assert_eq!(formatter.format(&path(&["a_b", "c"])).as_ref(), "ab_c");
+        assert_eq!(formatter.format(&path(&["a", "b_c"])).as_ref(), "a_bc");
+    }
+
+    #[test]
+    fn detects_key_path_collisions() {
+        let mut formatter = KeyPathFormatter::new(&PluginConfig {
+            key_segment_policy: Some(KeySegmentPolicy::Replace),
+            ..cfg()
+        });
+        let path = |segments: &[&'static str]| -> Vec<Cow<str>> {
+            segments.iter().map(|s| Cow::Borrowed(*s)).collect()
+        };
+
+        formatter.format(&path(&["http", "status"]));
+        formatter.format(&path(&["http", "status"]));
+        formatter.format(&path(&["a b"]));
+        assert!(formatter.collisions.is_empty());
+
+        formatter.format(&path(&["http.status"]));
+        formatter.format(&path(&["a_b"]));
+        formatter.format(&path(&["a?b"]));
+        let mut collisions: Vec<_> = formatter.collisions.iter().cloned().collect();
+        collisions.sort();
+        assert_eq!(collisions, vec!["a_b", "http.status"]);
+        assert_eq!(formatter.sources.len(), 2);
+    }
+
+    #[test]
+    fn applies_null_policy() {
+        let val = json!({"component": "c", "msg": "m", "reading": null});
+        let map = |null_policy| {
+            JsonEventMapper::new(&PluginConfig {
+                null_policy: Some(null_policy),
+                null_sentinel: Some("none".to_string()),
+                ..cfg()
+            })
+            .unwrap()
+            .map_json(&val, &[])
+        };
+        let reading = |ev: MappedEvent| {
+            ev.event_kvs
+                .into_iter()
+                .filter(|(k, _)| k.as_ref().starts_with("reading"))
+                .map(|(k, v)| (k.as_ref().to_string(), v))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(reading(map(NullPolicy::Drop).unwrap()), vec![]);
+        assert_eq!(
+            reading(map(NullPolicy::Sentinel).unwrap()),
+            vec![("reading".to_string(), s("none"))]
+        );
+        assert_eq!(
+            reading(map(NullPolicy::IsNull).unwrap()),
+            vec![("reading.is_null".to_string(), AttrVal::Bool(true))]
+        );
+        assert!(matches!(
+            map(NullPolicy::Fail),
+            Err(Error::NullValue(key)) if key.as_ref() == "reading"
+        ));
+    }
+
+    #[test]
+    fn requires_timeline_and_event_names() {
+        let mut mapper = JsonEventMapper::new(&cfg()).unwrap();
+        assert!(matches!(
+            mapper.map_json(&json!({"msg": "m"}), &[]),
+            Err(Error::MissingTimelineName)
+        ));
+        assert!(matches!(
+            mapper.map_json(&json!({"component": "c"}), &[]),
+            Err(Error::MissingEventName)
+        ));
+        assert!(matches!(
+            mapper.map_json(&json!([1, 2]), &[]),
+            Err(Error::ExpectedObject)
+        ));
+    }
+
+    #[test]
+    fn applies_name_prefixes() {
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            timeline_name_prefix: Some("tl-".to_string()),
+            event_name_prefix: Some("ev-".to_string()),
+            ..cfg()
+        })
+        .unwrap();
+        let ev = mapper
+            .map_json(&json!({"component": "c", "msg": "m"}), &[])
+            .unwrap();
+        assert!(ev
+            .timeline_kvs
+            .contains(&(AttrKey::new("name".to_string()), s("tl-c"))));
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("name".to_string()), s("ev-m"))));
+    }
+
+    #[test]
+    fn converts_timestamps() {
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            timestamp_attr: Some("ts".to_string()),
+            timestamp_attr_units: Some(TimestampUnit::Milliseconds),
+            ..cfg()
+        })
+        .unwrap();
+        let ev = mapper
+            .map_json(&json!({"component": "c", "msg": "m", "ts": 5}), &[])
+            .unwrap();
+        assert!(ev.event_kvs.contains(&(
+            AttrKey::new("timestamp".to_string()),
+            AttrVal::from(modality_api::Nanoseconds::from(5_000_000u64))
+        )));
+    }
+
+    #[test]
+    fn converts_coerced_timestamps() {
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            timestamp_attr: Some("ts".to_string()),
+            timestamp_attr_units: Some(TimestampUnit::Milliseconds),
+            coerce_attrs: vec![AttrCoercion {
+                path: "ts".to_string(),
+                to: CoercionType::Timestamp,
+                on_error: CoercionErrorAction::Fail,
+                units: Some(TimestampUnit::Seconds),
+            }],
+            ..cfg()
+        })
+        .unwrap();
+        let ev = mapper
+            .map_json(&json!({"component": "c", "msg": "m", "ts": "5"}), &[])
+            .unwrap();
+        assert!(ev.event_kvs.contains(&(
+            AttrKey::new("timestamp".to_string()),
+            AttrVal::from(modality_api::Nanoseconds::from(5_000_000_000u64))
+        )));
+    }
+
+    #[test]
+    fn coerces_attrs() {
+        let coercion = |on_error| AttrCoercion {
+            path: "code".to_string(),
+            to: CoercionType::HexInt,
+            on_error,
+            units: None,
+        };
+        let val = json!({"component": "c", "msg": "m", "code": "0x1f"});
+        let bad_val = json!({"component": "c", "msg": "m", "code": "nope"});
+
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            coerce_attrs: vec![coercion(CoercionErrorAction::Fail)],
+            ..cfg()
+        })
+        .unwrap();
+        let ev = mapper.map_json(&val, &[]).unwrap();
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("code".to_string()), AttrVal::Integer(31))));
+        assert!(matches!(
+            mapper.map_json(&bad_val, &[]),
+            Err(Error::Coercion { .. })
+        ));
+
+        let mut mapper = JsonEventMapper::new(&PluginConfig {
+            coerce_attrs: vec![coercion(CoercionErrorAction::Fallback)],
+            ..cfg()
+        })
+        .unwrap();
+        let ev = mapper.map_json(&bad_val, &[]).unwrap();
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("code".to_string()), s("nope"))));
+    }
+
+    #[test]
+    fn coerces_the_text_of_captures() {
+        let coercion = |path: &str, to| AttrCoercion {
+            path: path.to_string(),
+            to,
+            on_error: CoercionErrorAction::Fail,
+            units: None,
+        };
+        let cfg = PluginConfig {
+            non_json_regex: Some(r"^code=(?P<code>\w+) id=(?P<id>\w+) n=(?P<n>\w+)$".to_string()),
+            coerce_attrs: vec![
+                coercion("code", CoercionType::HexInt),
+                coercion("id", CoercionType::String),
+            ],
+            ..cfg()
+        };
+        let parser = NonJsonLineParser::new(&cfg).unwrap();
+
+        let Ok(NonJsonLine::Attrs(kvs)) = parser.parse_line("code=10 id=007 n=007") else {
+            panic!("expected attrs");
+        };
+        assert_eq!(
+            sorted(&kvs),
+            vec![
+                ("code".to_string(), AttrVal::Integer(16)),
+                ("id".to_string(), s("007")),
+                ("n".to_string(), AttrVal::Integer(7)),
+            ]
+        );
+
+        // The captures aren't coerced again when they're mapped
+        let mut mapper = JsonEventMapper::new(&cfg).unwrap();
+        let ev = mapper
+            .map_json(&json!({"component": "c", "msg": "m"}), &kvs)
+            .unwrap();
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("code".to_string()), AttrVal::Integer(16))));
+    }
+
+    #[test]
+    fn maps_attrs_with_another_config() {
+        let mut mapper = JsonEventMapper::new(&cfg()).unwrap();
+        let line_cfg = PluginConfig {
+            timeline_names: vec!["host".to_string()],
+            timeline_attrs: vec![],
+            event_names: vec!["level".to_string()],
+            ..Default::default()
+        };
+        let ev = mapper
+            .map_attrs_with_config(
+                vec![
+                    (AttrKey::new("host".to_string()), s("h1")),
+                    (AttrKey::new("level".to_string()), s("WARN")),
+                ],
+                &line_cfg,
+            )
+            .unwrap();
+        assert!(ev
+            .timeline_kvs
+            .contains(&(AttrKey::new("name".to_string()), s("h1"))));
+        assert!(ev
+            .event_kvs
+            .contains(&(AttrKey::new("name".to_string()), s("WARN"))));
+    }
+
+    #[test]
+    fn guesses_attr_val_types_of_strings() {
+        assert_eq!(string_to_attr_val("1.5"), AttrVal::Float(1.5.into()));
+        assert_eq!(string_to_attr_val("42"), BigInt::new_attr_val(42));
+        assert_eq!(string_to_attr_val("1.2.3"), s("1.2.3"));
+        assert_eq!(string_to_attr_val("abc"), s("abc"));
+    }
+
+    #[test]
+    fn parses_non_json_lines() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            non_json_regex: Some(r"^(?P<level>[A-Z]+): (?P<text>.*)$".to_string()),
+            ..cfg()
+        })
+        .unwrap();
+
+        let (line, rest) = parser.take_line("INFO: hello\n{}");
+        assert_eq!(line, "INFO: hello");
+        assert_eq!(rest, "\n{}");
+
+        let Ok(NonJsonLine::Attrs(kvs)) = parser.parse_line(line) else {
+            panic!("expected attrs");
+        };
+        assert_eq!(
+            sorted(&kvs),
+            vec![
+                ("level".to_string(), s("INFO")),
+                ("text".to_string(), s("hello")),
+            ]
+        );
+
+        assert!(matches!(
+            parser.parse_line("not a match"),
+            Err(Error::UnmatchedLine)
+        ));
+    }
+
+    #[test]
+    fn groups_lines_by_start_regex() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            multiline_start_regex: Some(r"^\d{4}-\d{2}-\d{2} ".to_string()),
+            ..cfg()
+        })
+        .unwrap();
+
+        let input = "2024-01-01 ERROR boom\n  at foo\ncaused by: bar\n2024-01-02 INFO ok\n{}";
+        let (record, rest) = parser.take_line(input);
+        assert_eq!(record, "2024-01-01 ERROR boom\n  at foo\ncaused by: bar");
+        assert_eq!(rest, "\n2024-01-02 INFO ok\n{}");
+
+        // A json value ends the record
+        let (record, rest) = parser.take_line(&rest[1..]);
+        assert_eq!(record, "2024-01-02 INFO ok");
+        assert_eq!(rest, "\n{}");
+    }
+
+    #[test]
+    fn groups_indented_lines() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            multiline_indent_continuation: true,
+            ..cfg()
+        })
+        .unwrap();
+
+        let input = "ERROR boom\r\n  at foo\r\n\tat bar\r\n  {\"component\": \"c\"}\r\nINFO ok";
+        let (record, rest) = parser.take_line(input);
+        assert_eq!(record, "ERROR boom\r\n  at foo\r\n\tat bar");
+        // An indented json value isn't a continuation
+        assert_eq!(rest, "\n  {\"component\": \"c\"}\r\nINFO ok");
+    }
+
+    #[test]
+    fn ends_multi_line_records_at_the_end_of_the_input() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            multiline_indent_continuation: true,
+            multiline_max_lines: Some(3),
+            ..cfg()
+        })
+        .unwrap();
+
+        assert_eq!(
+            parser.take_line("ERROR boom\n  at foo"),
+            ("ERROR boom\n  at foo", "")
+        );
+        assert_eq!(
+            parser.take_line("ERROR boom\n  at foo\n"),
+            ("ERROR boom\n  at foo", "\n")
+        );
+        assert_eq!(parser.take_line(""), ("", ""));
+
+        // Records are cut off at the maximum number of lines
+        assert_eq!(
+            parser.take_line("ERROR boom\n  a\n  b\n  c"),
+            ("ERROR boom\n  a\n  b", "\n  c")
+        );
+    }
+
+    #[test]
+    fn puts_continuation_lines_in_the_body_attr() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            non_json_regex: Some(r"^(?P<level>[A-Z]+) (?P<text>.*)".to_string()),
+            multiline_indent_continuation: true,
+            multiline_body_attr: Some("body".to_string()),
+            ..cfg()
+        })
+        .unwrap();
+
+        let (record, _) = parser.take_line("ERROR boom\n  at foo\n  at bar\nINFO ok");
+        let Ok(NonJsonLine::Attrs(kvs)) = parser.parse_line(record) else {
+            panic!("expected attrs");
+        };
+        assert_eq!(
+            sorted(&kvs),
+            vec![
+                ("body".to_string(), s("  at foo\n  at bar")),
+                ("level".to_string(), s("ERROR")),
+                ("text".to_string(), s("boom")),
+            ]
+        );
+
+        // A single line record has no body
+        let Ok(NonJsonLine::Attrs(kvs)) = parser.parse_line("INFO ok") else {
+            panic!("expected attrs");
+        };
+        assert_eq!(kvs.len(), 2);
+    }
+
+    #[test]
+    fn skips_unmatched_lines_when_configured() {
+        let parser = NonJsonLineParser::new(&PluginConfig {
+            non_json_regex: Some(r"^(?P<level>[A-Z]+):".to_string()),
+            unmatched_line_action: Some(UnmatchedLineAction::Skip),
+            ..cfg()
+        })
+        .unwrap();
+        assert!(matches!(
+            parser.parse_line("not a match"),
+            Ok(NonJsonLine::Skip)
+        ));
+    }
+
+    #[test]
+    fn applies_the_unmatched_line_action_without_rules() {
+        let parser = NonJsonLineParser::new(&cfg()).unwrap();
+        assert!(matches!(
+            parser.parse_line("not json"),
+            Err(Error::NoNonJsonRules)
+        ));
+
+        for action in [UnmatchedLineAction::Skip, UnmatchedLineAction::Warn] {
+            let parser = NonJsonLineParser::new(&PluginConfig {
+                unmatched_line_action: Some(action),
+                ..cfg()
+            })
+            .unwrap();
+            assert!(matches!(
+                parser.parse_line("not json"),
+                Ok(NonJsonLine::Skip)
+            ));
+        }
+    }
+
+    #[test]
+    fn splits_json_prefixes() {
+        let prefix = JsonPrefix::new(&PluginConfig {
+            json_prefix_regex: Some(r"^(?P<time>\S+) (?P<level>[A-Z]+)".to_string()),
+            ..cfg()
+        })
+        .unwrap()
+        .unwrap();
+
+        let (kvs, json) = prefix
+            .split("12:00:01 INFO {\"msg\": \"m\"}\nnext")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            sorted(&kvs),
+            vec![
+                ("level".to_string(), s("INFO")),
+                ("time".to_string(), s("12:00:01")),
+            ]
+        );
+        assert_eq!(json, "{\"msg\": \"m\"}\nnext");
+
+        assert!(prefix.split("12:00:01 INFO no json").unwrap().is_none());
+    }
+}
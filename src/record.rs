@@ -0,0 +1,212 @@
+use crate::connection::IngestConnection;
+use crate::error::Error;
+use crate::types::StoredAttrVal;
+use async_trait::async_trait;
+use modality_api::{AttrVal, TimelineId};
+use modality_ingest_protocol::InternedAttrKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+/// One ingest protocol message, as recorded by [`RecordingConnection`]. A recording is
+/// a file of these, one per line, as json.
+///
+/// This is externally tagged: serde buffers internally tagged enums in a way that
+/// doesn't support 128-bit integers, such as the event ordering.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordedMessage {
+    DeclareAttrKey {
+        key: String,
+        id: u32,
+    },
+    OpenTimeline {
+        id: Uuid,
+    },
+    TimelineMetadata {
+        attrs: Vec<(u32, StoredAttrVal)>,
+    },
+    Event {
+        ordering: u128,
+        attrs: Vec<(u32, StoredAttrVal)>,
+    },
+}
+
+/// An ingest connection which writes the ingest stream to a file instead of sending it,
+/// so that it can be replayed later with [`replay`]
+pub struct RecordingConnection {
+    out: BufWriter<File>,
+    next_key: u32,
+}
+
+impl RecordingConnection {
+    /// Record to the given file. With 'append' (for a resumed import), the stream is added to
+    /// the end of an existing recording instead of replacing it; attr keys are declared again,
+    /// so the recording can still be replayed.
+    pub fn create(path: &Path, append: bool) -> Result<Self, Error> {
+        let f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(Error::Recording)?;
+        Ok(Self {
+            out: BufWriter::new(f),
+            next_key: 0,
+        })
+    }
+
+    fn write(&mut self, msg: &RecordedMessage) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.out, msg).map_err(|e| Error::Recording(e.into()))?;
+        self.out.write_all(b"\n").map_err(Error::Recording)
+    }
+}
+
+fn stored_attrs(attrs: Vec<(InternedAttrKey, AttrVal)>) -> Vec<(u32, StoredAttrVal)> {
+    attrs
+        .iter()
+        .map(|(k, v)| (u32::from(*k), v.into()))
+        .collect()
+}
+
+#[async_trait]
+impl IngestConnection for RecordingConnection {
+    async fn declare_attr_key(&mut self, key: String) -> Result<InternedAttrKey, Error> {
+        self.next_key += 1;
+        let id = self.next_key;
+        self.write(&RecordedMessage::DeclareAttrKey { key, id })?;
+        Ok(InternedAttrKey::from(id))
+    }
+
+    async fn open_timeline(&mut self, id: TimelineId) -> Result<(), Error> {
+        self.write(&RecordedMessage::OpenTimeline { id: *id.get_raw() })
+    }
+
+    async fn timeline_metadata(
+        &mut self,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        self.write(&RecordedMessage::TimelineMetadata {
+            attrs: stored_attrs(attrs),
+        })
+    }
+
+    async fn event(
+        &mut self,
+        ordering: u128,
+        attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        self.write(&RecordedMessage::Event {
+            ordering,
+            attrs: stored_attrs(attrs),
+        })
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush().map_err(Error::Recording)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub timelines: usize,
+    pub events: u64,
+}
+
+/// Send a recorded ingest stream to the given connection. The recorded timeline ids are kept;
+/// attr keys are declared again, since their interned ids belong to the recording connection.
+pub async fn replay<R: BufRead>(
+    recording: R,
+    conn: &mut dyn IngestConnection,
+) -> Result<ReplayStats, Error> {
+    let mut keys: HashMap<u32, InternedAttrKey> = HashMap::new();
+    let mut timelines = std::collections::HashSet::new();
+    let mut stats = ReplayStats::default();
+
+    let intern = |keys: &HashMap<u32, InternedAttrKey>, attrs: Vec<(u32, StoredAttrVal)>| {
+        attrs
+            .into_iter()
+            .map(|(k, v)| {
+                let k = *keys.get(&k).ok_or(Error::UnknownRecordedAttrKey(k))?;
+                Ok((k, AttrVal::from(v)))
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    };
+
+    for (idx, line) in recording.lines().enumerate() {
+        let line = line.map_err(Error::Recording)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg: RecordedMessage =
+            serde_json::from_str(&line).map_err(|source| Error::InvalidRecording {
+                line: idx + 1,
+                source,
+            })?;
+
+        match msg {
+            RecordedMessage::DeclareAttrKey { key, id } => {
+                let k = conn.declare_attr_key(key).await?;
+                keys.insert(id, k);
+            }
+            RecordedMessage::OpenTimeline { id } => {
+                timelines.insert(id);
+                conn.open_timeline(TimelineId::from(id)).await?;
+            }
+            RecordedMessage::TimelineMetadata { attrs } => {
+                conn.timeline_metadata(intern(&keys, attrs)?).await?;
+            }
+            RecordedMessage::Event { ordering, attrs } => {
+                conn.event(ordering, intern(&keys, attrs)?).await?;
+                stats.events += 1;
+            }
+        }
+    }
+
+    conn.flush().await?;
+    stats.timelines = timelines.len();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn round_trips_every_message() {
+        let msgs = vec![
+            RecordedMessage::DeclareAttrKey {
+                key: "event.name".to_string(),
+                id: 1,
+            },
+            RecordedMessage::OpenTimeline { id: Uuid::new_v4() },
+            RecordedMessage::TimelineMetadata {
+                attrs: vec![
+                    (1, StoredAttrVal::String("sensor".to_string())),
+                    (2, StoredAttrVal::BigInt(i128::MIN)),
+                    (3, StoredAttrVal::TimelineId(Uuid::new_v4())),
+                ],
+            },
+            RecordedMessage::Event {
+                ordering: u128::MAX,
+                attrs: vec![
+                    (4, StoredAttrVal::Integer(-7)),
+                    (5, StoredAttrVal::Float(55.2)),
+                    (6, StoredAttrVal::Bool(true)),
+                    (7, StoredAttrVal::Timestamp(1_700_000_000_000_000_000)),
+                ],
+            },
+        ];
+
+        for msg in msgs {
+            let line = serde_json::to_string(&msg).unwrap();
+            let parsed: RecordedMessage = serde_json::from_str(&line).unwrap();
+            assert_eq!(parsed, msg, "{line}");
+        }
+    }
+}
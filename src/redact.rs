@@ -0,0 +1,217 @@
+use crate::config::{RedactionAction, RedactionRule};
+use crate::error::Error;
+use modality_api::{AttrKey, AttrVal};
+use regex::Regex;
+
+pub const REDACTION_KEY_ENV_VAR: &str = "MODALITY_JSON_REDACTION_KEY";
+
+const DEFAULT_MASK: &str = "****";
+const HASH_KEY_CONTEXT: &str = "modality-json-plugins 2024 redaction pseudonym";
+/// Pseudonyms keep 128 bits of the hash, so that distinct values practically never collide
+const PSEUDONYM_BYTES: usize = 16;
+
+/// Drops, masks, or hashes attr values according to the configured
+/// redaction rules. Rules are checked in order, and the first rule that
+/// selects an attr is applied to it.
+#[derive(Clone)]
+pub struct Redactor {
+    rules: Vec<CompiledRule>,
+    hash_key: Option<[u8; blake3::KEY_LEN]>,
+}
+
+#[derive(Clone)]
+struct CompiledRule {
+    path: Option<String>,
+    key_regex: Option<Regex>,
+    value_regex: Option<Regex>,
+    action: RedactionAction,
+    mask: String,
+}
+
+impl Redactor {
+    /// If no hash key is given, it's taken from the
+    /// MODALITY_JSON_REDACTION_KEY environment variable, when set.
+    pub fn new(rules: &[RedactionRule], hash_key: Option<&str>) -> Result<Self, Error> {
+        let compile = |re: &Option<String>| {
+            re.as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(Error::InvalidRedactionPattern)
+        };
+
+        let rules = rules
+            .iter()
+            .map(|r| {
+                Ok(CompiledRule {
+                    path: r.path.clone(),
+                    key_regex: compile(&r.key_regex)?,
+                    value_regex: compile(&r.value_regex)?,
+                    action: r.action,
+                    mask: r.mask.clone().unwrap_or_else(|| DEFAULT_MASK.to_string()),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let hash_key = hash_key
+            .map(|k| k.to_string())
+            .or_else(|| std::env::var(REDACTION_KEY_ENV_VAR).ok())
+            .map(|k| blake3::derive_key(HASH_KEY_CONTEXT, k.as_bytes()));
+
+        if hash_key.is_none() && rules.iter().any(|r| r.action == RedactionAction::Hash) {
+            return Err(Error::RedactionHashKeyRequired);
+        }
+
+        Ok(Self { rules, hash_key })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn redact(&self, kvs: &mut Vec<(AttrKey, AttrVal)>) {
+        kvs.retain_mut(|(key, val)| {
+            let Some(rule) = self.rules.iter().find(|r| r.selects(key.as_ref())) else {
+                return true;
+            };
+
+            match &rule.value_regex {
+                None => match rule.action {
+                    RedactionAction::Drop => return false,
+                    RedactionAction::Mask => *val = AttrVal::String(rule.mask.clone().into()),
+                    RedactionAction::Hash => {
+                        *val = AttrVal::String(self.pseudonym(&val.to_string()).into())
+                    }
+                },
+                Some(re) => {
+                    let s = val.to_string();
+                    if !re.is_match(&s) {
+                        return true;
+                    }
+                    let redacted = match rule.action {
+                        RedactionAction::Drop => return false,
+                        RedactionAction::Mask => re.replace_all(&s, regex::NoExpand(&rule.mask)),
+                        RedactionAction::Hash => {
+                            re.replace_all(&s, |caps: &regex::Captures| self.pseudonym(&caps[0]))
+                        }
+                    };
+                    *val = AttrVal::String(redacted.into_owned().into());
+                }
+            }
+
+            true
+        });
+    }
+
+    /// A stable pseudonym for the given value: the same value and key always
+    /// produce the same pseudonym
+    fn pseudonym(&self, value: &str) -> String {
+        let Some(key) = &self.hash_key else {
+            // Checked at construction time
+            unreachable!()
+        };
+        let hash = blake3::keyed_hash(key, value.as_bytes());
+        hex::encode(&hash.as_bytes()[..PSEUDONYM_BYTES])
+    }
+}
+
+impl CompiledRule {
+    fn selects(&self, key: &str) -> bool {
+        match (&self.path, &self.key_regex) {
+            (None, None) => true,
+            (path, key_regex) => {
+                path.as_deref() == Some(key)
+                    || key_regex.as_ref().map(|re| re.is_match(key)) == Some(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn redacted(
+        rules: &[RedactionRule],
+        hash_key: &str,
+        input: &[(&str, &str)],
+    ) -> Vec<(String, String)> {
+        let redactor = Redactor::new(rules, Some(hash_key)).unwrap();
+        let mut kvs = input
+            .iter()
+            .map(|(k, v)| (AttrKey::new(k.to_string()), AttrVal::String((*v).into())))
+            .collect();
+        redactor.redact(&mut kvs);
+        kvs.into_iter()
+            .map(|(k, v)| match v {
+                AttrVal::String(s) => (k.to_string(), s.to_string()),
+                v => panic!("unexpected attr val {v:?}"),
+            })
+            .collect()
+    }
+
+    fn rule(path: &str, action: RedactionAction) -> RedactionRule {
+        RedactionRule {
+            path: Some(path.to_string()),
+            action,
+            ..Default::default()
+        }
+    }
+
+    fn kv(k: &str, v: &str) -> (String, String) {
+        (k.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn drops_masks_and_hashes() {
+        let rules = [
+            rule("password", RedactionAction::Drop),
+            rule("token", RedactionAction::Mask),
+            rule("user", RedactionAction::Hash),
+            RedactionRule {
+                key_regex: Some("^msg$".to_string()),
+                value_regex: Some("[a-z]+@example\\.com".to_string()),
+                action: RedactionAction::Mask,
+                mask: Some("<email>".to_string()),
+                ..Default::default()
+            },
+        ];
+        let out = redacted(
+            &rules,
+            "secret",
+            &[
+                ("password", "hunter2"),
+                ("token", "abc123"),
+                ("user", "alice"),
+                ("msg", "mail bob@example.com now"),
+                ("other", "kept"),
+            ],
+        );
+
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0], kv("token", "****"));
+        assert_eq!(out[1].0, "user");
+        assert_eq!(out[1].1.len(), PSEUDONYM_BYTES * 2);
+        assert!(out[1].1.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(out[2], kv("msg", "mail <email> now"));
+        assert_eq!(out[3], kv("other", "kept"));
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        let rules = [RedactionRule {
+            value_regex: Some("[a-z]+".to_string()),
+            ..rule("user", RedactionAction::Hash)
+        }];
+        let hash = |key: &str, value: &str| redacted(&rules, key, &[("user", value)])[0].1.clone();
+
+        // The same value and key always produce the same pseudonym, whole or as part of a value
+        assert_eq!(hash("secret", "alice"), hash("secret", "alice"));
+        assert_eq!(
+            hash("secret", "alice+bob"),
+            format!("{}+{}", hash("secret", "alice"), hash("secret", "bob"))
+        );
+        assert_ne!(hash("secret", "alice"), hash("secret", "bob"));
+        assert_ne!(hash("secret", "alice"), hash("other secret", "alice"));
+    }
+}
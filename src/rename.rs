@@ -0,0 +1,207 @@
+use crate::config::{AttrKeyRename, RenameKind};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// How many distinct keys [`AttrKeyRenamer`] remembers the renamed form of. Keys beyond
+/// this are renamed each time they're seen.
+const MAX_CACHED_KEYS: usize = 10_000;
+
+/// Applies an ordered list of attr key renames. Keys are normalized to
+/// start with the given prefix ('event.' or 'timeline.') both before and
+/// after renaming. All renames match the key with the prefix removed; the
+/// prefix is removed from exact renames' original keys too. The first
+/// matching rename wins.
+pub struct AttrKeyRenamer {
+    prefix: &'static str,
+    rules: Vec<RenameRule>,
+    cache: HashMap<String, String>,
+}
+
+enum RenameRule {
+    Exact { original: String, new: String },
+    Pattern { re: Regex, replacement: String },
+}
+
+impl AttrKeyRenamer {
+    pub fn new(prefix: &'static str, renames: Vec<AttrKeyRename>) -> Result<Self, regex::Error> {
+        let rules = renames
+            .into_iter()
+            .map(|r| {
+                Ok(match r.kind {
+                    RenameKind::Exact => RenameRule::Exact {
+                        original: unprefix_key(prefix, r.original),
+                        new: r.new,
+                    },
+                    RenameKind::Regex => RenameRule::Pattern {
+                        re: Regex::new(&format!("^(?:{})$", r.original))?,
+                        replacement: r.new,
+                    },
+                    RenameKind::Glob => {
+                        let (re, replacement) = glob_to_regex(&r.original, &r.new);
+                        RenameRule::Pattern {
+                            re: Regex::new(&re)?,
+                            replacement,
+                        }
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+
+        Ok(Self {
+            prefix,
+            rules,
+            cache: Default::default(),
+        })
+    }
+
+    /// Normalize and rename the given key
+    pub fn rename(&mut self, key: &str) -> String {
+        if let Some(renamed) = self.cache.get(key) {
+            return renamed.clone();
+        }
+
+        let normalized = normalize_key(self.prefix, key.to_string());
+        let unprefixed = &normalized[self.prefix.len()..];
+
+        let mut renamed = None;
+        for rule in self.rules.iter() {
+            match rule {
+                RenameRule::Exact { original, new } => {
+                    if original == unprefixed {
+                        renamed = Some(new.clone());
+                    }
+                }
+                RenameRule::Pattern { re, replacement } => {
+                    if re.is_match(unprefixed) {
+                        renamed = Some(re.replace(unprefixed, replacement.as_str()).into_owned());
+                    }
+                }
+            }
+
+            if renamed.is_some() {
+                break;
+            }
+        }
+
+        let renamed = match renamed {
+            Some(new) => normalize_key(self.prefix, new),
+            None => normalized,
+        };
+        if self.cache.len() < MAX_CACHED_KEYS {
+            self.cache.insert(key.to_string(), renamed.clone());
+        }
+        renamed
+    }
+
+    /// Rename the given key, and remove the prefix from the result
+    pub fn rename_unprefixed(&mut self, key: &str) -> String {
+        let mut renamed = self.rename(key);
+        renamed.drain(..self.prefix.len());
+        renamed
+    }
+}
+
+pub fn normalize_key(prefix: &str, s: String) -> String {
+    if s.starts_with(prefix) {
+        s
+    } else {
+        format!("{prefix}{s}")
+    }
+}
+
+fn unprefix_key(prefix: &str, mut s: String) -> String {
+    if s.starts_with(prefix) {
+        s.drain(..prefix.len());
+    }
+    s
+}
+
+/// Translate a glob rename into an anchored regex and a regex replacement
+/// string, where the nth '*' in 'new' refers to the nth '*' in 'original'.
+fn glob_to_regex(original: &str, new: &str) -> (String, String) {
+    let re = original
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join("(.*)");
+
+    let mut replacement = String::new();
+    for (i, part) in new.split('*').enumerate() {
+        if i > 0 {
+            replacement.push_str(&format!("${{{i}}}"));
+        }
+        replacement.push_str(&part.replace('$', "$$"));
+    }
+
+    (format!("^{re}$"), replacement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn rename(original: &str, new: &str, kind: RenameKind) -> AttrKeyRename {
+        AttrKeyRename {
+            original: original.to_string(),
+            new: new.to_string(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn translates_globs() {
+        assert_eq!(
+            glob_to_regex("ctx.*", "context.*"),
+            ("^ctx\\.(.*)$".to_string(), "context.${1}".to_string())
+        );
+        assert_eq!(
+            glob_to_regex("*.*_ms", "*.*.millis"),
+            (
+                "^(.*)\\.(.*)_ms$".to_string(),
+                "${1}.${2}.millis".to_string()
+            )
+        );
+        assert_eq!(
+            glob_to_regex("price", "cost$"),
+            ("^price$".to_string(), "cost$$".to_string())
+        );
+    }
+
+    #[test]
+    fn glob_dots_match_only_dots() {
+        let mut renamer = AttrKeyRenamer::new(
+            "event.",
+            vec![rename("ctx.*", "context.*", RenameKind::Glob)],
+        )
+        .unwrap();
+        assert_eq!(renamer.rename("ctx.user.id"), "event.context.user.id");
+        assert_eq!(renamer.rename("ctxXuser"), "event.ctxXuser");
+    }
+
+    #[test]
+    fn all_renames_match_the_unprefixed_key() {
+        let mut renamer = AttrKeyRenamer::new(
+            "timeline.",
+            vec![
+                rename("component", "source", RenameKind::Exact),
+                rename("timeline.host", "machine", RenameKind::Exact),
+                rename("build_(.*)", "build.$1", RenameKind::Regex),
+                rename("run*", "run.*", RenameKind::Glob),
+            ],
+        )
+        .unwrap();
+        assert_eq!(renamer.rename("component"), "timeline.source");
+        assert_eq!(renamer.rename("timeline.component"), "timeline.source");
+        assert_eq!(renamer.rename("host"), "timeline.machine");
+        assert_eq!(renamer.rename("timeline.build_id"), "timeline.build.id");
+        assert_eq!(renamer.rename_unprefixed("run_id"), "run._id");
+        // Patterns don't see the prefix
+        let mut renamer = AttrKeyRenamer::new(
+            "timeline.",
+            vec![rename("timeline\\.(.*)", "tl.$1", RenameKind::Regex)],
+        )
+        .unwrap();
+        assert_eq!(renamer.rename("timeline.name"), "timeline.name");
+    }
+}
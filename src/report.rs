@@ -0,0 +1,83 @@
+use modality_api::TimelineId;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// A summary of what an import did
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub files_processed: u64,
+    pub bytes_processed: u64,
+    /// Json values and non-json lines read from the inputs
+    pub records: u64,
+    /// Records which couldn't be parsed or mapped, and were skipped in lenient mode
+    pub records_skipped: u64,
+    /// Non-json lines which were discarded or matched no rule
+    pub records_filtered: u64,
+    pub events_sent: u64,
+    pub events_per_timeline: BTreeMap<String, u64>,
+    pub timelines_created: u64,
+    pub attr_keys_declared: u64,
+    pub wall_time_secs: f64,
+
+    #[serde(skip)]
+    timeline_counts: HashMap<TimelineId, (String, u64)>,
+}
+
+impl ImportReport {
+    /// Count an event sent on the given timeline. 'timeline_name' is only
+    /// called the first time a timeline is seen.
+    pub fn count_event(&mut self, timeline_id: TimelineId, timeline_name: impl FnOnce() -> String) {
+        self.events_sent += 1;
+        self.timeline_counts
+            .entry(timeline_id)
+            .or_insert_with(|| (timeline_name(), 0))
+            .1 += 1;
+    }
+
+    /// Whether any events have been counted on the given timeline
+    pub fn has_timeline(&self, timeline_id: &TimelineId) -> bool {
+        self.timeline_counts.contains_key(timeline_id)
+    }
+
+    /// Fill in the derived totals
+    pub fn finish(&mut self, wall_time: Duration, attr_keys_declared: u64) {
+        self.wall_time_secs = wall_time.as_secs_f64();
+        self.attr_keys_declared = attr_keys_declared;
+        self.timelines_created = self.timeline_counts.len() as u64;
+        self.events_per_timeline.clear();
+        for (name, count) in self.timeline_counts.values() {
+            *self.events_per_timeline.entry(name.clone()).or_default() += count;
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<(), std::io::Error> {
+        let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(f, self)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Import summary:")?;
+        writeln!(f, "  Files processed:    {}", self.files_processed)?;
+        writeln!(f, "  Bytes processed:    {}", self.bytes_processed)?;
+        writeln!(f, "  Records:            {}", self.records)?;
+        writeln!(f, "  Records skipped:    {}", self.records_skipped)?;
+        writeln!(f, "  Records filtered:   {}", self.records_filtered)?;
+        writeln!(f, "  Events sent:        {}", self.events_sent)?;
+        writeln!(f, "  Timelines created:  {}", self.timelines_created)?;
+        writeln!(f, "  Attr keys declared: {}", self.attr_keys_declared)?;
+        write!(f, "  Wall time:          {:.3}s", self.wall_time_secs)?;
+        if !self.events_per_timeline.is_empty() {
+            write!(f, "\n  Events per timeline:")?;
+            for (name, count) in self.events_per_timeline.iter() {
+                write!(f, "\n    {name}: {count}")?;
+            }
+        }
+        Ok(())
+    }
+}
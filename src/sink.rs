@@ -0,0 +1,365 @@
+use crate::config::AttrKeyRename;
+use crate::error::Error;
+use crate::rename::AttrKeyRenamer;
+use crate::types::StoredAttrVal;
+use async_trait::async_trait;
+use modality_api::{AttrKey, AttrVal, TimelineId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Where mapped events go: modality (via [`Client`](crate::client::Client)), or one of the
+/// sinks in this module
+#[async_trait]
+pub trait EventSink: Send {
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error>;
+
+    async fn flush(&mut self) -> Result<(), Error>;
+
+    /// The number of distinct attr keys declared so far, for sinks which declare them
+    fn declared_attr_key_count(&self) -> usize {
+        0
+    }
+}
+
+/// Renames attr keys, then passes events on to another sink. The renamed keys don't have
+/// the 'timeline.' or 'event.' prefix; a [`Client`](crate::client::Client) adds it back.
+pub struct RenamingSink {
+    inner: Box<dyn EventSink>,
+    rename_timeline_attrs: AttrKeyRenamer,
+    rename_event_attrs: AttrKeyRenamer,
+}
+
+impl RenamingSink {
+    pub fn new(
+        inner: Box<dyn EventSink>,
+        rename_timeline_attrs: Vec<AttrKeyRename>,
+        rename_event_attrs: Vec<AttrKeyRename>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            inner,
+            rename_timeline_attrs: AttrKeyRenamer::new("timeline.", rename_timeline_attrs)?,
+            rename_event_attrs: AttrKeyRenamer::new("event.", rename_event_attrs)?,
+        })
+    }
+}
+
+fn rename_kvs(
+    renamer: &mut AttrKeyRenamer,
+    kvs: Vec<(AttrKey, AttrVal)>,
+) -> Vec<(AttrKey, AttrVal)> {
+    kvs.into_iter()
+        .map(|(k, v)| (AttrKey::new(renamer.rename_unprefixed(k.as_ref())), v))
+        .collect()
+}
+
+#[async_trait]
+impl EventSink for RenamingSink {
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        let timeline_kvs = rename_kvs(&mut self.rename_timeline_attrs, timeline_kvs);
+        let event_kvs = rename_kvs(&mut self.rename_event_attrs, event_kvs);
+        self.inner
+            .send_event_on_timeline(timeline_id, timeline_kvs, ordering, event_kvs)
+            .await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush().await
+    }
+
+    fn declared_attr_key_count(&self) -> usize {
+        self.inner.declared_attr_key_count()
+    }
+}
+
+/// Prints events to stdout, one per line, for dry runs
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+#[async_trait]
+impl EventSink for StdoutSink {
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        let name = |kvs: &[(AttrKey, AttrVal)]| {
+            kvs.iter()
+                .find(|(k, _)| k.as_ref() == "name")
+                .map(|(_, v)| v.to_string())
+        };
+        let timeline_name = name(&timeline_kvs).unwrap_or_else(|| timeline_id.to_string());
+        let event_name = name(&event_kvs).unwrap_or_default();
+        let attrs = event_kvs
+            .iter()
+            .filter(|(k, _)| k.as_ref() != "name")
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{event_name} @ {timeline_name} [{ordering}] {attrs}").map_err(Error::Sink)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        std::io::stdout().flush().map_err(Error::Sink)
+    }
+}
+
+/// A mapped event, as written by [`FileSink`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SinkRecord {
+    pub timeline_id: Uuid,
+    pub timeline_attrs: BTreeMap<String, StoredAttrVal>,
+    pub ordering: u128,
+    pub event_attrs: BTreeMap<String, StoredAttrVal>,
+}
+
+/// Writes events to a file, one json [`SinkRecord`] per line
+pub struct FileSink {
+    out: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let f = File::create(path).map_err(Error::Sink)?;
+        Ok(Self {
+            out: BufWriter::new(f),
+        })
+    }
+}
+
+fn stored_attrs(kvs: &[(AttrKey, AttrVal)]) -> BTreeMap<String, StoredAttrVal> {
+    kvs.iter()
+        .map(|(k, v)| (k.to_string(), StoredAttrVal::from(v)))
+        .collect()
+}
+
+#[async_trait]
+impl EventSink for FileSink {
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        let record = SinkRecord {
+            timeline_id: *timeline_id.get_raw(),
+            timeline_attrs: stored_attrs(&timeline_kvs),
+            ordering,
+            event_attrs: stored_attrs(&event_kvs),
+        };
+        serde_json::to_writer(&mut self.out, &record).map_err(|e| Error::Sink(e.into()))?;
+        self.out.write_all(b"\n").map_err(Error::Sink)
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush().map_err(Error::Sink)
+    }
+}
+
+/// An event collected by [`MemorySink`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SunkEvent {
+    pub timeline_id: TimelineId,
+    pub timeline_kvs: Vec<(AttrKey, AttrVal)>,
+    pub ordering: u128,
+    pub event_kvs: Vec<(AttrKey, AttrVal)>,
+}
+
+/// Collects events in memory. Clones share the same events, so one clone can
+/// be given to the pipeline and another used to inspect what it sent.
+#[derive(Clone, Debug, Default)]
+pub struct MemorySink {
+    events: Arc<Mutex<Vec<SunkEvent>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> Vec<SunkEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EventSink for MemorySink {
+    async fn send_event_on_timeline(
+        &mut self,
+        timeline_id: TimelineId,
+        timeline_kvs: Vec<(AttrKey, AttrVal)>,
+        ordering: u128,
+        event_kvs: Vec<(AttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        self.events.lock().unwrap().push(SunkEvent {
+            timeline_id,
+            timeline_kvs,
+            ordering,
+            event_kvs,
+        });
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PluginConfig, RenameKind};
+    use crate::mapper::JsonEventMapper;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::io::BufRead;
+
+    fn kvs(kvs: &[(&str, AttrVal)]) -> Vec<(AttrKey, AttrVal)> {
+        kvs.iter()
+            .map(|(k, v)| (AttrKey::new(k.to_string()), v.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn renames_before_the_inner_sink() {
+        let memory = MemorySink::new();
+        let mut sink = RenamingSink::new(
+            Box::new(memory.clone()),
+            vec![AttrKeyRename {
+                original: "timeline.component".to_string(),
+                new: "timeline.source".to_string(),
+                kind: RenameKind::Exact,
+            }],
+            vec![AttrKeyRename {
+                original: "temp_*".to_string(),
+                new: "temperature.*".to_string(),
+                kind: RenameKind::Glob,
+            }],
+        )
+        .unwrap();
+
+        sink.send_event_on_timeline(
+            TimelineId::allocate(),
+            kvs(&[("component", "sensor".into())]),
+            0,
+            kvs(&[("name", "m".into()), ("temp_c", AttrVal::Integer(55))]),
+        )
+        .await
+        .unwrap();
+
+        let events = memory.events();
+        assert_eq!(events[0].timeline_kvs, kvs(&[("source", "sensor".into())]));
+        assert_eq!(
+            events[0].event_kvs,
+            kvs(&[
+                ("name", "m".into()),
+                ("temperature.c", AttrVal::Integer(55))
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn collects_mapped_events() {
+        let cfg = PluginConfig {
+            timeline_names: vec!["component".to_string()],
+            event_names: vec!["msg".to_string()],
+            ..Default::default()
+        };
+        let mut mapper = JsonEventMapper::new(&cfg).unwrap();
+        let memory = MemorySink::new();
+        let mut sink: Box<dyn EventSink> = Box::new(memory.clone());
+
+        let records = [
+            json!({"component": "monitor", "msg": "startup"}),
+            json!({"component": "sensor", "msg": "measure", "temperature": 55.2}),
+            json!({"component": "monitor", "msg": "report"}),
+        ];
+        for (ordering, record) in records.iter().enumerate() {
+            let ev = mapper.map_json(record, &[]).unwrap();
+            sink.send_event_on_timeline(
+                ev.timeline_id,
+                ev.timeline_kvs,
+                ordering as u128,
+                ev.event_kvs,
+            )
+            .await
+            .unwrap();
+        }
+        sink.flush().await.unwrap();
+
+        let events = memory.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].timeline_id, events[2].timeline_id);
+        assert_ne!(events[0].timeline_id, events[1].timeline_id);
+        assert_eq!(
+            events.iter().map(|e| e.ordering).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert!(events[1].event_kvs.contains(&(
+            AttrKey::new("temperature".to_string()),
+            AttrVal::Float(55.2.into())
+        )));
+    }
+
+    #[tokio::test]
+    async fn file_sink_records_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let timeline_id = TimelineId::allocate();
+        let timeline_kvs = kvs(&[("name", "sensor".into())]);
+        let event_kvs = kvs(&[
+            ("name", "measure".into()),
+            ("temperature", AttrVal::Float(55.2.into())),
+            ("big", modality_api::BigInt::new_attr_val(i128::MIN)),
+        ]);
+
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.send_event_on_timeline(
+            timeline_id,
+            timeline_kvs.clone(),
+            u128::MAX,
+            event_kvs.clone(),
+        )
+        .await
+        .unwrap();
+        sink.flush().await.unwrap();
+
+        let lines: Vec<String> = std::io::BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(lines.len(), 1);
+        let record: SinkRecord = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            record,
+            SinkRecord {
+                timeline_id: *timeline_id.get_raw(),
+                timeline_attrs: stored_attrs(&timeline_kvs),
+                ordering: u128::MAX,
+                event_attrs: stored_attrs(&event_kvs),
+            }
+        );
+    }
+}
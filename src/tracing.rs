@@ -1,4 +1,6 @@
-pub fn try_init_tracing_subscriber() -> Result<(), Box<dyn std::error::Error>> {
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+
+pub fn try_init_tracing_subscriber() -> Result<(), TryInitError> {
     let builder = tracing_subscriber::fmt::Subscriber::builder();
     let env_filter = std::env::var(tracing_subscriber::EnvFilter::DEFAULT_ENV)
         .map(tracing_subscriber::EnvFilter::new)
@@ -13,7 +15,5 @@ pub fn try_init_tracing_subscriber() -> Result<(), Box<dyn std::error::Error>> {
         });
     let builder = builder.with_env_filter(env_filter);
     let subscriber = builder.finish();
-    use tracing_subscriber::util::SubscriberInitExt;
-    subscriber.try_init()?;
-    Ok(())
+    subscriber.try_init()
 }
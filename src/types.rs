@@ -1,21 +1,47 @@
+use modality_api::{AttrVal, BigInt, Nanoseconds, TimelineId};
+use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
 use std::sync::Arc;
+use tokio::sync::Notify;
+use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 #[repr(transparent)]
-pub struct Interruptor(Arc<AtomicBool>);
+pub struct Interruptor(Arc<InterruptorState>);
+
+#[derive(Debug)]
+struct InterruptorState {
+    set: AtomicBool,
+    notify: Notify,
+}
 
 impl Interruptor {
     pub fn new() -> Self {
-        Interruptor(Arc::new(AtomicBool::new(false)))
+        Interruptor(Arc::new(InterruptorState {
+            set: AtomicBool::new(false),
+            notify: Notify::new(),
+        }))
     }
 
     pub fn set(&self) {
-        self.0.store(true, SeqCst);
+        self.0.set.store(true, SeqCst);
+        self.0.notify.notify_waiters();
     }
 
     pub fn is_set(&self) -> bool {
-        self.0.load(SeqCst)
+        self.0.set.load(SeqCst)
+    }
+
+    /// Wait until the interruptor is set
+    pub async fn interrupted(&self) {
+        loop {
+            // Registered before checking, so a concurrent 'set' isn't missed
+            let notified = self.0.notify.notified();
+            if self.is_set() {
+                return;
+            }
+            notified.await;
+        }
     }
 }
 
@@ -24,3 +50,46 @@ impl Default for Interruptor {
         Self::new()
     }
 }
+
+/// A serializable representation of an [`AttrVal`], used for files written by the
+/// importer (checkpoint state, recorded ingest streams)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "t", content = "v", rename_all = "kebab-case")]
+pub enum StoredAttrVal {
+    String(String),
+    Integer(i64),
+    BigInt(i128),
+    Float(f64),
+    Bool(bool),
+    Timestamp(u64),
+    TimelineId(Uuid),
+}
+
+impl From<&AttrVal> for StoredAttrVal {
+    fn from(v: &AttrVal) -> Self {
+        match v {
+            AttrVal::Integer(i) => StoredAttrVal::Integer(*i),
+            AttrVal::BigInt(i) => StoredAttrVal::BigInt(*i.as_ref()),
+            AttrVal::Float(f) => StoredAttrVal::Float(f.0),
+            AttrVal::Bool(b) => StoredAttrVal::Bool(*b),
+            AttrVal::Timestamp(ns) => StoredAttrVal::Timestamp(ns.get_raw()),
+            AttrVal::TimelineId(id) => StoredAttrVal::TimelineId(*id.get_raw()),
+            // Strings, and the types the importer never produces, are kept in their string form
+            _ => StoredAttrVal::String(v.to_string()),
+        }
+    }
+}
+
+impl From<StoredAttrVal> for AttrVal {
+    fn from(v: StoredAttrVal) -> Self {
+        match v {
+            StoredAttrVal::String(s) => AttrVal::String(s.into()),
+            StoredAttrVal::Integer(i) => AttrVal::Integer(i),
+            StoredAttrVal::BigInt(i) => BigInt::new_attr_val(i),
+            StoredAttrVal::Float(f) => AttrVal::Float(f.into()),
+            StoredAttrVal::Bool(b) => AttrVal::Bool(b),
+            StoredAttrVal::Timestamp(ns) => AttrVal::from(Nanoseconds::from(ns)),
+            StoredAttrVal::TimelineId(id) => AttrVal::from(TimelineId::from(id)),
+        }
+    }
+}
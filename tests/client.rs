@@ -0,0 +1,340 @@
+mod support;
+
+use async_trait::async_trait;
+use modality_api::{AttrKey, AttrVal, TimelineId};
+use modality_ingest_client::IngestClient;
+use modality_ingest_protocol::InternedAttrKey;
+use modality_json::client::Client;
+use modality_json::config::{AttrKeyRename, RenameKind};
+use modality_json::connection::{IngestConnection, IngestConnector, RetryPolicy};
+use modality_json::error::Error;
+use modality_json::sink::{EventSink, RenamingSink};
+use modality_json::types::Interruptor;
+use pretty_assertions::assert_eq;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use support::MockIngestServer;
+
+async fn connect(server: &MockIngestServer) -> Client {
+    let c = IngestClient::connect_with_timeout(&server.url(), false, Duration::from_secs(1))
+        .await
+        .unwrap();
+    let c_authed = c.authenticate(vec![0xab]).await.unwrap();
+    Client::new(c_authed, 2)
+}
+
+fn kvs(kvs: &[(&str, AttrVal)]) -> Vec<(AttrKey, AttrVal)> {
+    kvs.iter()
+        .map(|(k, v)| (AttrKey::new(k.to_string()), v.clone()))
+        .collect()
+}
+
+#[tokio::test]
+async fn sends_events_and_timeline_metadata() {
+    let server = MockIngestServer::start().await;
+    let mut client = connect(&server).await;
+
+    let monitor = TimelineId::allocate();
+    let sensor = TimelineId::allocate();
+    for (ordering, (tl, name)) in [(monitor, "a"), (sensor, "b"), (monitor, "c")]
+        .into_iter()
+        .enumerate()
+    {
+        let tl_name = if tl == monitor { "monitor" } else { "sensor" };
+        client
+            .send_event_on_timeline(
+                tl,
+                kvs(&[("name", tl_name.into())]),
+                ordering as u128,
+                kvs(&[
+                    ("name", name.into()),
+                    ("n", AttrVal::Integer(ordering as i64)),
+                ]),
+            )
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let received = server.wait_for_disconnect().await;
+    assert_eq!(received.auth_tokens, vec![vec![0xab]]);
+    assert_eq!(received.event_names_on("monitor"), vec!["a", "c"]);
+    assert_eq!(received.event_names_on("sensor"), vec!["b"]);
+
+    let (_, tl) = received.timeline_named("monitor").unwrap();
+    assert_eq!(
+        tl.attrs.get("timeline.name"),
+        Some(&AttrVal::String("monitor".into()))
+    );
+
+    let events = received.events_on(monitor);
+    assert_eq!(events[1].ordering, 2);
+    assert_eq!(events[1].attrs.get("event.n"), Some(&AttrVal::Integer(2)));
+
+    // Each key is declared once, even though it was used on several timelines and batches
+    let mut declared_keys = received.declared_keys.clone();
+    declared_keys.sort();
+    assert_eq!(
+        declared_keys,
+        vec!["event.n", "event.name", "timeline.name"]
+    );
+}
+
+#[tokio::test]
+async fn sends_changed_timeline_metadata() {
+    let server = MockIngestServer::start().await;
+    let mut client = connect(&server).await;
+
+    let tl = TimelineId::allocate();
+    for (ordering, state) in ["starting", "running"].into_iter().enumerate() {
+        client
+            .send_event_on_timeline(
+                tl,
+                kvs(&[("name", "tl".into()), ("state", state.into())]),
+                ordering as u128,
+                kvs(&[("name", "ev".into())]),
+            )
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    drop(client);
+
+    let received = server.wait_for_disconnect().await;
+    let (_, tl) = received.timeline_named("tl").unwrap();
+    assert_eq!(
+        tl.attrs.get("timeline.state"),
+        Some(&AttrVal::String("running".into()))
+    );
+    assert_eq!(received.events.len(), 2);
+}
+
+#[tokio::test]
+async fn renames_event_attr_keys() {
+    let server = MockIngestServer::start().await;
+    let renames = vec![AttrKeyRename {
+        original: "^msg_(.*)$".to_string(),
+        new: "message.$1".to_string(),
+        kind: RenameKind::Regex,
+    }];
+    // As in the importer, renames are done in front of the client
+    let mut client = RenamingSink::new(Box::new(connect(&server).await), vec![], renames).unwrap();
+
+    client
+        .send_event_on_timeline(
+            TimelineId::allocate(),
+            kvs(&[("name", "tl".into())]),
+            0,
+            kvs(&[("name", "ev".into()), ("msg_text", "hello".into())]),
+        )
+        .await
+        .unwrap();
+    client.flush().await.unwrap();
+    drop(client);
+
+    let received = server.wait_for_disconnect().await;
+    assert_eq!(
+        received.events[0].attrs.get("event.message.text"),
+        Some(&AttrVal::String("hello".into()))
+    );
+}
+
+/// What the fake connections received: the event orderings sent on each connection, and
+/// the number of flushes
+#[derive(Debug, Default)]
+struct ConnectionLog {
+    events: Vec<Vec<u128>>,
+    flushes: usize,
+}
+
+/// A connection which fails, with a real connection error, when asked to send its
+/// 'fail_at'th event
+struct FlakyConnection {
+    log: Arc<Mutex<ConnectionLog>>,
+    index: usize,
+    next_key: u32,
+    fail_at: Option<usize>,
+}
+
+#[async_trait]
+impl IngestConnection for FlakyConnection {
+    async fn declare_attr_key(&mut self, _key: String) -> Result<InternedAttrKey, Error> {
+        self.next_key += 1;
+        Ok(InternedAttrKey::from(self.next_key))
+    }
+
+    async fn open_timeline(&mut self, _id: TimelineId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn timeline_metadata(
+        &mut self,
+        _attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn event(
+        &mut self,
+        ordering: u128,
+        _attrs: Vec<(InternedAttrKey, AttrVal)>,
+    ) -> Result<(), Error> {
+        if self.fail_at == Some(self.log.lock().unwrap().events[self.index].len()) {
+            let url = "modality-ingest://127.0.0.1:1".parse().unwrap();
+            let err = IngestClient::connect_with_timeout(&url, false, Duration::from_secs(1))
+                .await
+                .err()
+                .unwrap();
+            return Err(err.into());
+        }
+        self.log.lock().unwrap().events[self.index].push(ordering);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.log.lock().unwrap().flushes += 1;
+        Ok(())
+    }
+}
+
+struct FlakyConnector {
+    log: Arc<Mutex<ConnectionLog>>,
+}
+
+impl FlakyConnector {
+    fn connection(&self, fail_at: Option<usize>) -> Box<dyn IngestConnection> {
+        let mut log = self.log.lock().unwrap();
+        log.events.push(Vec::new());
+        Box::new(FlakyConnection {
+            log: self.log.clone(),
+            index: log.events.len() - 1,
+            next_key: 0,
+            fail_at,
+        })
+    }
+}
+
+#[async_trait]
+impl IngestConnector for FlakyConnector {
+    async fn connect(&self) -> Result<Box<dyn IngestConnection>, Error> {
+        Ok(self.connection(None))
+    }
+}
+
+#[tokio::test]
+async fn replays_unflushed_batches_after_a_disconnect_mid_batch() {
+    let log = Arc::new(Mutex::new(ConnectionLog::default()));
+    let connector = FlakyConnector { log: log.clone() };
+    // The first connection fails partway through the second batch
+    let conn = connector.connection(Some(3));
+    let retry_policy = RetryPolicy {
+        max_attempts: 1,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    };
+    let mut client =
+        Client::with_connection(conn, 2).with_reconnect(Box::new(connector), retry_policy);
+
+    let tl = TimelineId::allocate();
+    for ordering in 0..6 {
+        client
+            .send_event_on_timeline(
+                tl,
+                kvs(&[("name", "tl".into())]),
+                ordering,
+                kvs(&[("name", "ev".into())]),
+            )
+            .await
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.events, vec![vec![0, 1, 2], vec![0, 1, 2, 3, 4, 5]]);
+    assert_eq!(log.flushes, 1);
+}
+
+/// A connector which can't reconnect, failing with the given error
+struct RefusingConnector {
+    attempts: Arc<Mutex<usize>>,
+    err: fn() -> Error,
+}
+
+#[async_trait]
+impl IngestConnector for RefusingConnector {
+    async fn connect(&self) -> Result<Box<dyn IngestConnection>, Error> {
+        *self.attempts.lock().unwrap() += 1;
+        Err((self.err)())
+    }
+}
+
+async fn send_one_event(client: &mut Client) -> Result<(), Error> {
+    let tl = TimelineId::allocate();
+    client
+        .send_event_on_timeline(
+            tl,
+            kvs(&[("name", "tl".into())]),
+            0,
+            kvs(&[("name", "ev".into())]),
+        )
+        .await?;
+    client.flush().await
+}
+
+#[tokio::test]
+async fn doesnt_retry_authentication_failures() {
+    let log = Arc::new(Mutex::new(ConnectionLog::default()));
+    let conn = FlakyConnector { log }.connection(Some(0));
+    let attempts = Arc::new(Mutex::new(0));
+    let connector = RefusingConnector {
+        attempts: attempts.clone(),
+        err: || {
+            let denied = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+            Error::Authentication(Box::new(Error::Sink(denied)))
+        },
+    };
+    let retry_policy = RetryPolicy {
+        max_attempts: 5,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    };
+    let mut client =
+        Client::with_connection(conn, 2).with_reconnect(Box::new(connector), retry_policy);
+
+    let err = send_one_event(&mut client).await.unwrap_err();
+    assert!(matches!(err, Error::Authentication(_)), "{err}");
+    assert_eq!(*attempts.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn stops_reconnecting_when_interrupted() {
+    let log = Arc::new(Mutex::new(ConnectionLog::default()));
+    let conn = FlakyConnector { log }.connection(Some(0));
+    let attempts = Arc::new(Mutex::new(0));
+    let connector = RefusingConnector {
+        attempts: attempts.clone(),
+        err: || unreachable!("the client shouldn't reconnect once interrupted"),
+    };
+    let retry_policy = RetryPolicy {
+        max_attempts: 5,
+        initial_backoff: Duration::from_secs(60),
+        max_backoff: Duration::from_secs(60),
+    };
+    let interruptor = Interruptor::new();
+    let mut client = Client::with_connection(conn, 2)
+        .with_reconnect(Box::new(connector), retry_policy)
+        .with_interruptor(interruptor.clone());
+
+    let interrupt = async {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        interruptor.set();
+    };
+    let (res, ()) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(send_one_event(&mut client), interrupt)
+    })
+    .await
+    .expect("the backoff should be cut short by the interrupt");
+    assert!(res.unwrap_err().is_connection_error());
+    assert_eq!(*attempts.lock().unwrap(), 0);
+}
@@ -0,0 +1,640 @@
+mod support;
+
+use modality_api::{AttrVal, TimelineId};
+use modality_json::record::RecordedMessage;
+use pretty_assertions::assert_eq;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use support::MockIngestServer;
+
+const README_DATA: &str = r#"{"component": "monitor", "timestamp": 1, "msg": "startup"}
+{"component": "sensor", "timestamp": 2, "msg": "measure temperature", "temperature": 55.2}
+{"component": "sensor", "timestamp": 3, "msg": "send measurement", "dest": "monitor", "seqnum": 1}
+{"component": "monitor", "timestamp": 4, "msg": "recv measurement", "src": "sensor", "seqnum": 1, "temperature": 55.2}
+{"component": "monitor", "timestamp": 5, "msg": "report status"}
+"#;
+
+const README_CONFIG: &str = r#"
+[plugins.ingest.importers.json.metadata]
+timeline-names = ['component']
+timeline-attrs = ['component']
+event-names = ['msg']
+timestamp-attr = 'timestamp'
+timestamp-attr-units = 's'
+"#;
+
+/// Write the README example's data and reflector config to a temp dir
+fn readme_example() -> (tempfile::TempDir, PathBuf, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("data.json");
+    let config = dir.path().join("my-reflector-config.toml");
+    std::fs::write(&data, README_DATA).unwrap();
+    std::fs::write(&config, README_CONFIG).unwrap();
+    (dir, data, config)
+}
+
+async fn run(bin: &'static str, args: Vec<String>) -> Output {
+    tokio::task::spawn_blocking(move || {
+        Command::new(bin)
+            .args(args)
+            .env_remove("MODALITY_REFLECTOR_CONFIG")
+            .output()
+            .unwrap()
+    })
+    .await
+    .unwrap()
+}
+
+async fn import(config: &Path, extra_args: &[&str], input: &Path) -> Output {
+    let mut args = vec![
+        "--config".to_string(),
+        config.display().to_string(),
+        "--auth-token".to_string(),
+        "00".to_string(),
+        "--progress".to_string(),
+        "none".to_string(),
+    ];
+    args.extend(extra_args.iter().map(|s| s.to_string()));
+    args.push(input.display().to_string());
+
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+    assert!(
+        output.status.success(),
+        "import failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn assert_readme_example_received(received: &support::Received) {
+    assert_eq!(received.timelines.len(), 2);
+    assert_eq!(
+        received.event_names_on("monitor"),
+        vec!["startup", "recv measurement", "report status"]
+    );
+    assert_eq!(
+        received.event_names_on("sensor"),
+        vec!["measure temperature", "send measurement"]
+    );
+
+    let (sensor_id, sensor) = received.timeline_named("sensor").unwrap();
+    assert_eq!(
+        sensor.attrs.get("timeline.component"),
+        Some(&AttrVal::String("sensor".into()))
+    );
+    assert!(sensor.attrs.contains_key("timeline.run_id"));
+
+    let send = received.events_on(sensor_id)[1];
+    assert_eq!(send.attrs.get("event.seqnum"), Some(&AttrVal::Integer(1)));
+    assert_eq!(
+        send.attrs.get("event.dest"),
+        Some(&AttrVal::String("monitor".into()))
+    );
+    assert!(send.attrs.contains_key("event.timestamp"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn imports_readme_example() {
+    let server = MockIngestServer::start().await;
+    let (_dir, data, config) = readme_example();
+
+    let url = server.url().to_string();
+    import(&config, &["--ingest-protocol-parent-url", &url], &data).await;
+
+    let received = server.wait_for_disconnect().await;
+    assert_readme_example_received(&received);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uses_the_given_run_id() {
+    let server = MockIngestServer::start().await;
+    let (_dir, data, config) = readme_example();
+
+    let url = server.url().to_string();
+    let run_id = "6f4b1a5e-0f1a-4b0e-9d5c-2a8f9e0c1b2d";
+    import(
+        &config,
+        &["--ingest-protocol-parent-url", &url, "--run-id", run_id],
+        &data,
+    )
+    .await;
+
+    let received = server.wait_for_disconnect().await;
+    for tl in received.timelines.values() {
+        assert_eq!(
+            tl.attrs.get("timeline.run_id"),
+            Some(&AttrVal::String(run_id.into()))
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn records_and_replays_readme_example() {
+    let server = MockIngestServer::start().await;
+    let (dir, data, config) = readme_example();
+
+    // Recording doesn't need a server
+    let recording = dir.path().join("recording.jsonl");
+    import(
+        &config,
+        &["--record", &recording.display().to_string()],
+        &data,
+    )
+    .await;
+
+    let recorded_timelines: Vec<_> = std::fs::read_to_string(&recording)
+        .unwrap()
+        .lines()
+        .filter_map(|l| match serde_json::from_str(l).unwrap() {
+            RecordedMessage::OpenTimeline { id } => Some(id),
+            _ => None,
+        })
+        .collect();
+
+    let output = run(
+        env!("CARGO_BIN_EXE_modality-json-replay"),
+        vec![
+            "--auth-token".to_string(),
+            "00".to_string(),
+            "--ingest-protocol-parent-url".to_string(),
+            server.url().to_string(),
+            recording.display().to_string(),
+        ],
+    )
+    .await;
+    assert!(
+        output.status.success(),
+        "replay failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let received = server.wait_for_disconnect().await;
+    assert_readme_example_received(&received);
+    for id in recorded_timelines {
+        assert!(received.timelines.contains_key(&TimelineId::from(id)));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_prints_events_without_connecting() {
+    let (_dir, data, config) = readme_example();
+
+    // No server, and an unreachable ingest url: a dry run never connects
+    let output = import(
+        &config,
+        &[
+            "--dry-run",
+            "--ingest-protocol-parent-url",
+            "modality-ingest://127.0.0.1:1",
+        ],
+        &data,
+    )
+    .await;
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("startup @ monitor [0]"));
+    assert!(lines[2].starts_with("send measurement @ sensor [1]"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dry_run_does_not_write_the_state_file() {
+    let (dir, data, config) = readme_example();
+    let state_file = dir.path().join("state.json");
+
+    import(
+        &config,
+        &[
+            "--dry-run",
+            "--state-file",
+            &state_file.display().to_string(),
+        ],
+        &data,
+    )
+    .await;
+
+    assert!(!state_file.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_parsing_imports_inputs_in_order() {
+    let (dir, _data, config) = readme_example();
+    let lines: Vec<&str> = README_DATA.lines().collect();
+    let first = dir.path().join("first.json");
+    let second = dir.path().join("second.json");
+    std::fs::write(&first, lines[..2].join("\n")).unwrap();
+    std::fs::write(&second, lines[2..].join("\n")).unwrap();
+
+    let first = first.display().to_string();
+    let output = import(
+        &config,
+        &["--dry-run", "--parse-jobs", "2", &first],
+        &second,
+    )
+    .await;
+
+    // Each input has its own orderings for its timelines
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let events: Vec<&str> = stdout
+        .lines()
+        .map(|l| &l[..l.find(']').unwrap() + 1])
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "startup @ monitor [0]",
+            "measure temperature @ sensor [0]",
+            "send measurement @ sensor [0]",
+            "recv measurement @ monitor [0]",
+            "report status @ monitor [1]",
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chunked_ndjson_keeps_file_order_and_locations() {
+    let (dir, data, config) = readme_example();
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(3, r#"{"component": "sensor" "msg": "oops"}"#);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+    let dead_letters = dir.path().join("dead-letters.jsonl");
+
+    // A chunk for every line
+    let output = import(
+        &config,
+        &[
+            "--dry-run",
+            "--ndjson",
+            "--ndjson-chunk-size",
+            "1",
+            "--lenient",
+            "--dead-letter-file",
+            &dead_letters.display().to_string(),
+        ],
+        &data,
+    )
+    .await;
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let events: Vec<&str> = stdout
+        .lines()
+        .map(|l| &l[..l.find(']').unwrap() + 1])
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "startup @ monitor [0]",
+            "measure temperature @ sensor [0]",
+            "send measurement @ sensor [1]",
+            "recv measurement @ monitor [1]",
+            "report status @ monitor [2]",
+        ]
+    );
+
+    let dead_letter: serde_json::Value =
+        serde_json::from_str(std::fs::read_to_string(&dead_letters).unwrap().trim()).unwrap();
+    assert_eq!(dead_letter["line"], 4);
+    let line_start = lines[..3].join("\n").len() + 1;
+    let offset = dead_letter["offset"].as_u64().unwrap() as usize;
+    assert!((line_start..line_start + lines[3].len()).contains(&offset));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn truncated_record_does_not_drop_the_next_one() {
+    let (dir, data, config) = readme_example();
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(1, r#"{"component": "sensor", "msg": "trunc"#);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+    let dead_letters = dir.path().join("dead-letters.jsonl");
+
+    let output = import(
+        &config,
+        &[
+            "--dry-run",
+            "--lenient",
+            "--dead-letter-file",
+            &dead_letters.display().to_string(),
+        ],
+        &data,
+    )
+    .await;
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 5);
+    assert!(stdout
+        .lines()
+        .nth(1)
+        .unwrap()
+        .starts_with("measure temperature @ sensor"));
+
+    let dead_letter: serde_json::Value =
+        serde_json::from_str(std::fs::read_to_string(&dead_letters).unwrap().trim()).unwrap();
+    assert_eq!(dead_letter["line"], 2);
+    assert_eq!(dead_letter["record"].as_str().unwrap().trim(), lines[1]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn broken_pretty_printed_record_is_one_error() {
+    let (dir, data, config) = readme_example();
+    let broken = r#"{
+  "component": "sensor",
+  "msg": "broken"
+  "reading": {
+    "values": [
+      {"v": 1}
+    ]
+  }
+}"#;
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(1, broken);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+    let dead_letters = dir.path().join("dead-letters.jsonl");
+
+    let output = import(
+        &config,
+        &[
+            "--dry-run",
+            "--lenient",
+            "--max-errors",
+            "1",
+            "--dead-letter-file",
+            &dead_letters.display().to_string(),
+        ],
+        &data,
+    )
+    .await;
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 5);
+
+    // The whole object is a single dead letter, located at the missing comma
+    let dead_letter: serde_json::Value =
+        serde_json::from_str(std::fs::read_to_string(&dead_letters).unwrap().trim()).unwrap();
+    assert_eq!(dead_letter["line"], 5);
+    assert_eq!(dead_letter["record"].as_str().unwrap().trim(), broken);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resume_refuses_a_changed_input() {
+    let (dir, data, config) = readme_example();
+    let recording = dir.path().join("recording.jsonl").display().to_string();
+    let state_file = dir.path().join("state.json");
+    let state_arg = state_file.display().to_string();
+
+    import(
+        &config,
+        &["--record", &recording, "--state-file", &state_arg],
+        &data,
+    )
+    .await;
+
+    // Each timeline has its own ordering counter
+    let mut state: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&state_file).unwrap()).unwrap();
+    let input = &mut state["inputs"][data.display().to_string()];
+    let mut orderings: Vec<u64> = input["orderings"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|o| o.as_u64().unwrap())
+        .collect();
+    orderings.sort();
+    assert_eq!(orderings, vec![2, 3]);
+    assert_eq!(input["fingerprint"]["len"], README_DATA.len());
+
+    // Pretend the import stopped part way through, then change the input
+    input["complete"] = false.into();
+    std::fs::write(&state_file, state.to_string()).unwrap();
+    std::fs::write(&data, format!("{README_DATA}{README_DATA}")).unwrap();
+
+    let args = [
+        "--config",
+        &config.display().to_string(),
+        "--auth-token",
+        "00",
+        "--progress",
+        "none",
+        "--resume",
+        "--record",
+        &recording,
+        "--state-file",
+        &state_arg,
+        &data.display().to_string(),
+    ]
+    .map(str::to_string)
+    .to_vec();
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("has changed since it was checkpointed"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumed_record_run_appends_to_the_recording() {
+    let (dir, data, config) = readme_example();
+    let recording = dir.path().join("recording.jsonl");
+    let recording_arg = recording.display().to_string();
+    let state_file = dir.path().join("state.json");
+    let state_arg = state_file.display().to_string();
+    let record_args = ["--record", &recording_arg, "--state-file", &state_arg];
+
+    import(&config, &record_args, &data).await;
+    let first_run = std::fs::read_to_string(&recording).unwrap();
+
+    // Pretend the import stopped after the first three records
+    let mut state: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&state_file).unwrap()).unwrap();
+    let input = &mut state["inputs"][data.display().to_string()];
+    input["complete"] = false.into();
+    input["offset"] = README_DATA
+        .lines()
+        .take(3)
+        .map(|l| l.len() + 1)
+        .sum::<usize>()
+        .into();
+    std::fs::write(&state_file, state.to_string()).unwrap();
+
+    let mut resume_args = vec!["--resume"];
+    resume_args.extend(record_args);
+    import(&config, &resume_args, &data).await;
+
+    let recorded = std::fs::read_to_string(&recording).unwrap();
+    assert!(recorded.starts_with(&first_run));
+    let events = recorded
+        .lines()
+        .map(|l| serde_json::from_str::<RecordedMessage>(l).unwrap())
+        .filter(|m| matches!(m, RecordedMessage::Event { .. }))
+        .count();
+    assert_eq!(events, 5 + 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ndjson_rejects_attached_non_json_lines() {
+    let (_dir, data, config) = readme_example();
+
+    let args = [
+        "--config",
+        &config.display().to_string(),
+        "--dry-run",
+        "--ndjson",
+        "--non-json-regex",
+        "^(?P<level>[A-Z]+):",
+        &data.display().to_string(),
+    ]
+    .map(str::to_string)
+    .to_vec();
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("ndjson inputs"), "{stderr}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dead_letters_require_opting_out_of_redaction() {
+    let (dir, data, config) = readme_example();
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(1, r#"{"component": "sensor", "secret": "hunter2"#);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            "{README_CONFIG}\n[[plugins.ingest.importers.json.metadata.redact]]\npath = 'secret'\n"
+        ),
+    )
+    .unwrap();
+    let dead_letters = dir.path().join("dead-letters.jsonl");
+    let args = |extra: &[&str]| {
+        let mut args = vec![
+            "--config".to_string(),
+            config.display().to_string(),
+            "--dry-run".to_string(),
+            "--lenient".to_string(),
+            "--dead-letter-file".to_string(),
+            dead_letters.display().to_string(),
+        ];
+        args.extend(extra.iter().map(|s| s.to_string()));
+        args.push(data.display().to_string());
+        args
+    };
+
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args(&[])).await;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--dead-letter-unredacted"), "{stderr}");
+    assert!(!dead_letters.exists());
+
+    let output = run(
+        env!("CARGO_BIN_EXE_modality-json-importer"),
+        args(&["--dead-letter-unredacted"]),
+    )
+    .await;
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&dead_letters)
+        .unwrap()
+        .contains("hunter2"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn max_errors_reports_the_last_error() {
+    let (_dir, data, config) = readme_example();
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(2, r#"{"component": "sensor" "msg": "oops"}"#);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+
+    let args = [
+        "--config",
+        &config.display().to_string(),
+        "--dry-run",
+        "--lenient",
+        "--max-errors",
+        "0",
+        &data.display().to_string(),
+    ]
+    .map(str::to_string)
+    .to_vec();
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("maximum number of errors (0)"), "{stderr}");
+    assert!(
+        stderr.contains(&format!("{}:3:", data.display())),
+        "{stderr}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_import_writes_a_partial_report() {
+    let (dir, data, config) = readme_example();
+    let mut lines: Vec<&str> = README_DATA.lines().collect();
+    lines.insert(0, "[]");
+    lines.insert(3, r#"{"component": "sensor" "msg": "oops"}"#);
+    std::fs::write(&data, lines.join("\n")).unwrap();
+    let report_json = dir.path().join("report.json");
+
+    let args = [
+        "--config",
+        &config.display().to_string(),
+        "--dry-run",
+        "--lenient",
+        "--max-errors",
+        "0",
+        "--report-json",
+        &report_json.display().to_string(),
+        &data.display().to_string(),
+    ]
+    .map(str::to_string)
+    .to_vec();
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("the summary below is partial"), "{stderr}");
+
+    // The empty array isn't a record
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_json).unwrap()).unwrap();
+    assert_eq!(report["records"], 2);
+    assert_eq!(report["events_sent"], 2);
+
+    // A recording on /dev/full fails to flush at the first checkpoint
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    std::fs::write(&data, README_DATA).unwrap();
+    let args = [
+        "--config",
+        &config.display().to_string(),
+        "--auth-token",
+        "00",
+        "--progress",
+        "none",
+        "--record",
+        "/dev/full",
+        "--state-file",
+        &dir.path().join("state.json").display().to_string(),
+        "--checkpoint-interval",
+        "1",
+        "--report-json",
+        &report_json.display().to_string(),
+        &data.display().to_string(),
+    ]
+    .map(str::to_string)
+    .to_vec();
+    let output = run(env!("CARGO_BIN_EXE_modality-json-importer"), args).await;
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("the summary below is partial"), "{stderr}");
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report_json).unwrap()).unwrap();
+    assert_eq!(report["records"], 1);
+    assert_eq!(report["events_sent"], 1);
+}
@@ -0,0 +1,241 @@
+//! A local stand-in for modalityd's ingest endpoint, for tests. It speaks the ingest
+//! protocol (length-prefixed CBOR messages) over plain TCP, accepts any auth token,
+//! and records the keys, timelines and events it receives.
+
+#![allow(dead_code)]
+
+use modality_api::{AttrVal, TimelineId};
+use modality_ingest_protocol::{IngestMessage, InternedAttrKey};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+pub struct MockIngestServer {
+    addr: SocketAddr,
+    received: Arc<Mutex<Received>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Received {
+    pub connections: usize,
+    pub closed_connections: usize,
+    pub auth_tokens: Vec<Vec<u8>>,
+    /// Every declared attr key name, in the order received
+    pub declared_keys: Vec<String>,
+    pub timelines: HashMap<TimelineId, ReceivedTimeline>,
+    /// Every event, in the order received
+    pub events: Vec<ReceivedEvent>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReceivedTimeline {
+    pub attrs: BTreeMap<String, AttrVal>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceivedEvent {
+    pub timeline_id: TimelineId,
+    pub ordering: u128,
+    pub attrs: BTreeMap<String, AttrVal>,
+}
+
+impl Received {
+    /// The timeline whose 'timeline.name' attr is `name`
+    pub fn timeline_named(&self, name: &str) -> Option<(TimelineId, &ReceivedTimeline)> {
+        self.timelines
+            .iter()
+            .find(|(_, tl)| tl.attrs.get("timeline.name") == Some(&AttrVal::String(name.into())))
+            .map(|(id, tl)| (*id, tl))
+    }
+
+    /// The events on the given timeline, by ordering
+    pub fn events_on(&self, timeline_id: TimelineId) -> Vec<&ReceivedEvent> {
+        let mut events: Vec<_> = self
+            .events
+            .iter()
+            .filter(|ev| ev.timeline_id == timeline_id)
+            .collect();
+        events.sort_by_key(|ev| ev.ordering);
+        events
+    }
+
+    /// The 'event.name' attrs of the events on the timeline with the given name, by ordering
+    pub fn event_names_on(&self, timeline_name: &str) -> Vec<String> {
+        let Some((id, _)) = self.timeline_named(timeline_name) else {
+            return vec![];
+        };
+        self.events_on(id)
+            .into_iter()
+            .filter_map(|ev| ev.attrs.get("event.name").map(|v| v.to_string()))
+            .collect()
+    }
+}
+
+impl MockIngestServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Received::default()));
+
+        let task_received = received.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                task_received.lock().unwrap().connections += 1;
+                let conn_received = task_received.clone();
+                tokio::spawn(async move {
+                    // Protocol errors show up as missing data in the test's assertions
+                    let _ = serve_connection(stream, &conn_received).await;
+                    conn_received.lock().unwrap().closed_connections += 1;
+                });
+            }
+        });
+
+        MockIngestServer {
+            addr,
+            received,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("modality-ingest://{}", self.addr)).unwrap()
+    }
+
+    /// A copy of everything received so far
+    pub fn received(&self) -> Received {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Wait until what's been received satisfies `cond`, panicking after a few seconds
+    pub async fn wait_for(&self, cond: impl Fn(&Received) -> bool) -> Received {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let received = self.received();
+            if cond(&received) {
+                return received;
+            }
+            if Instant::now() > deadline {
+                panic!("Timed out waiting for the mock ingest server; received {received:#?}");
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Wait until every connection made so far has been closed by the client
+    pub async fn wait_for_disconnect(&self) -> Received {
+        self.wait_for(|r| r.connections > 0 && r.closed_connections == r.connections)
+            .await
+    }
+}
+
+impl Drop for MockIngestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_connection(
+    mut stream: TcpStream,
+    received: &Mutex<Received>,
+) -> std::io::Result<()> {
+    let mut keys: HashMap<InternedAttrKey, String> = HashMap::new();
+    let mut current_timeline = None;
+
+    let named = |keys: &HashMap<InternedAttrKey, String>,
+                 attrs: Vec<(InternedAttrKey, AttrVal)>| {
+        attrs
+            .into_iter()
+            .map(|(k, v)| {
+                let name = keys
+                    .get(&k)
+                    .cloned()
+                    .unwrap_or_else(|| format!("<undeclared key {}>", u32::from(k)));
+                (name, v)
+            })
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    while let Some(msg) = read_message(&mut stream).await? {
+        if let IngestMessage::AuthRequest { token } = msg {
+            received.lock().unwrap().auth_tokens.push(token);
+            let response = IngestMessage::AuthResponse {
+                ok: true,
+                message: None,
+            };
+            write_message(&mut stream, &response).await?;
+            continue;
+        }
+
+        let mut received = received.lock().unwrap();
+        match msg {
+            IngestMessage::DeclareAttrKey { name, wire_id } => {
+                received.declared_keys.push(name.clone());
+                keys.insert(wire_id, name);
+            }
+            IngestMessage::OpenTimeline { id } => {
+                received.timelines.entry(id).or_default();
+                current_timeline = Some(id);
+            }
+            IngestMessage::TimelineMetadata { attrs } => {
+                let Some(id) = current_timeline else {
+                    continue;
+                };
+                let attrs = named(&keys, attrs.0);
+                received
+                    .timelines
+                    .entry(id)
+                    .or_default()
+                    .attrs
+                    .extend(attrs);
+            }
+            IngestMessage::Event { be_ordering, attrs } => {
+                let Some(timeline_id) = current_timeline else {
+                    continue;
+                };
+                let ordering = be_ordering
+                    .iter()
+                    .fold(0u128, |acc, b| (acc << 8) | *b as u128);
+                received.events.push(ReceivedEvent {
+                    timeline_id,
+                    ordering,
+                    attrs: named(&keys, attrs.0),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+async fn read_message(stream: &mut TcpStream) -> std::io::Result<Option<IngestMessage>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    let msg = minicbor::decode(&buf)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Some(msg))
+}
+
+async fn write_message(stream: &mut TcpStream, msg: &IngestMessage) -> std::io::Result<()> {
+    let buf = minicbor::to_vec(msg)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+    stream.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}